@@ -0,0 +1,253 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::models::*;
+use crate::models::ErrorType::*;
+
+// Control flow of the compiled story, blocks being followed through their calls
+struct Analysis<'a> {
+    program: &'a Program,
+    entries: HashMap<usize, usize>,    // Block starting at each address
+    returns: Vec<bool>,                 // Blocks having a path to their `RET`
+}
+
+// Reports the parts of the story that can never be reached, the jumps going
+// outside of the program and the loops that have no way out
+pub fn analyse(program: &Program) -> Vec<Error> {
+    let mut analysis = Analysis::new(program);
+    analysis.returning();
+
+    let mut diagnostics = Vec::new();
+    let reachable = analysis.reachable(0, true, &mut diagnostics);
+
+    // Blocks never reached have their whole body unreachable
+    let mut skipped = vec![false; program.byte_code.len()];
+    for (block, definition) in program.blocks.iter().enumerate() {
+        if reachable.contains(&definition.address) {
+            if !analysis.returns[block] && analysis.recursive(block) {
+                diagnostics.push(Error::warning(
+                    EndlessBlock(definition.name.clone()), definition.span.clone()
+                ).with_help("a branch option not calling the block again would end the loop"));
+            }
+            continue;
+        }
+
+        skipped[definition.address..definition.end].fill(true);
+        // Blocks never called at all are warned about by the compiler already
+        if definition.used {
+            diagnostics.push(Error::warning(
+                UncalledBlock(definition.name.clone()), definition.span.clone()
+            ).with_help("it's only called from blocks that are never called themselves"));
+        }
+    }
+
+    // Runs of unreachable instructions are reported once
+    let mut unreachable: Option<Span> = None;
+    for (address, op_code) in program.byte_code.iter().enumerate() {
+        let visible = !matches!(op_code, OpCode::NOP | OpCode::JMP(_) | OpCode::RET | OpCode::END);
+        if reachable.contains(&address) || skipped[address] {
+            if let Some(span) = unreachable.take() {
+                diagnostics.push(Error::warning(UnreachableCode, span));
+            }
+        }
+        else if visible {
+            let span = &program.spans[address];
+            unreachable = Some(match unreachable {
+                Some(start) => start.to(span),
+                None => span.clone(),
+            });
+        }
+    }
+    if let Some(span) = unreachable {
+        diagnostics.push(Error::warning(UnreachableCode, span));
+    }
+
+    for &address in &reachable {
+        if analysis.endless_jump(address) {
+            diagnostics.push(Error::warning(EndlessJump, program.spans[address].clone()));
+        }
+    }
+
+    diagnostics
+}
+
+impl<'a> Analysis<'a> {
+    fn new(program: &'a Program) -> Self {
+        Self {
+            program,
+            entries: program.blocks.iter().enumerate()
+                .map(|(block, definition)| (definition.address, block))
+                .collect(),
+            returns: vec![false; program.blocks.len()],
+        }
+    }
+
+    // A block returns if it's `RET` is reachable, calls continuing only past
+    // the blocks known to return, till nothing changes anymore
+    fn returning(&mut self) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in 0..self.program.blocks.len() {
+                if self.returns[block] {
+                    continue;
+                }
+
+                let address = self.program.blocks[block].address;
+                let reachable = self.reachable(address, false, &mut Vec::new());
+                let byte_code = &self.program.byte_code;
+                if reachable.iter().any(|&address| matches!(byte_code[address], OpCode::RET)) {
+                    self.returns[block] = true;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    // Blocks calling only other endless blocks are not endless loops themselves
+    fn recursive(&self, block: usize) -> bool {
+        let address = self.program.blocks[block].address;
+        let reachable = self.reachable(address, true, &mut Vec::new());
+        reachable.iter().any(|&call| self.calls(&self.program.byte_code[call], address))
+    }
+
+    fn calls(&self, op_code: &OpCode, address: usize) -> bool {
+        match op_code {
+            OpCode::CALL(target, _) => *target == address,
+            OpCode::BRANCH(_, branches) => branches.iter()
+                .any(|branch| self.calls(&branch.handler, address)),
+            _ => false,
+        }
+    }
+
+    // Every instruction reachable from `start`, entering called blocks only if
+    // `follow_calls`, jumps outside of the program are reported on the way
+    fn reachable(
+        &self, start: usize, follow_calls: bool, diagnostics: &mut Vec<Error>
+    ) -> HashSet<usize> {
+        let mut reachable = HashSet::new();
+        let mut pending = vec![start];
+        while let Some(address) = pending.pop() {
+            if !reachable.insert(address) {
+                continue;
+            }
+
+            let mut targets = Vec::new();
+            self.successors(&self.program.byte_code[address], address, follow_calls, &mut targets);
+            for target in targets {
+                if target < self.program.byte_code.len() {
+                    pending.push(target);
+                }
+                else {
+                    diagnostics.push(Error::at(
+                        InvalidJump(target), self.program.spans[address].clone()
+                    ));
+                }
+            }
+        }
+
+        reachable
+    }
+
+    // Branch handlers run at the address of their branch
+    fn successors(
+        &self, op_code: &OpCode, address: usize, follow_calls: bool, targets: &mut Vec<usize>
+    ) {
+        match op_code {
+            OpCode::NOP | OpCode::TELL(_) | OpCode::ASK(..) | OpCode::ASSERT(..) => {
+                targets.push(address + 1)
+            },
+            OpCode::JMP(target) => targets.push(*target),
+            OpCode::BRANCH(_, branches) => for branch in branches {
+                self.successors(&branch.handler, address, follow_calls, targets);
+            },
+            OpCode::CALL(target, _) => {
+                if follow_calls {
+                    targets.push(*target);
+                }
+                // Calls to anything other than a block are assumed to return
+                let returns = self.entries.get(target).is_none_or(|&block| self.returns[block]);
+                if returns {
+                    targets.push(address + 1);
+                }
+            },
+            OpCode::RET | OpCode::END => (),
+        }
+    }
+
+    // Jumps and no-ops leading back to themselves
+    // The loop is only reported from the lowest address of it
+    fn endless_jump(&self, start: usize) -> bool {
+        let mut address = start;
+        let mut visited = HashSet::new();
+        while visited.insert(address) {
+            match self.program.byte_code.get(address) {
+                Some(OpCode::NOP | OpCode::ASSERT(..)) => address += 1,
+                Some(OpCode::JMP(target)) => address = *target,
+                _ => return false,
+            }
+        }
+        address == start && visited.iter().all(|&address| address >= start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::compile;
+
+    fn diagnostics(program: &Program) -> Vec<(usize, ErrorType)> {
+        analyse(program).into_iter()
+            .map(|diagnostic| (diagnostic.span.start.line, diagnostic.error_type))
+            .collect()
+    }
+
+    const LOOP: &str = "\
+again := {
+    # \"Again?\" {
+        \"Yes\" => again
+        \"Sure\" => again
+    }
+}
+again
+- \"Bye\"
+";
+
+    #[test]
+    fn endless_block() {
+        let program = compile(LOOP).unwrap();
+        assert_eq!(diagnostics(&program), vec![
+            (1, EndlessBlock("again".to_string())),
+            (8, UnreachableCode),
+        ]);
+
+        let program = compile(&LOOP.replace("\"Sure\" => again", "\"No\" => - \"Ok\"")).unwrap();
+        assert!(diagnostics(&program).is_empty());
+    }
+
+    #[test]
+    fn uncalled_block() {
+        let program = compile("f := { - \"f\" }\ng := { f }\n- \"Hi\"\n").unwrap();
+        assert_eq!(diagnostics(&program), vec![(1, UncalledBlock("f".to_string()))]);
+    }
+
+    // Runs of unreachable statements are reported once, spanning all of them
+    #[test]
+    fn unreachable_code() {
+        let program = compile(&format!("{}- \"Really\"\n", LOOP)).unwrap();
+        let warnings = analyse(&program);
+        let unreachable = warnings.iter().find(|warning| warning.error_type == UnreachableCode);
+        let span = &unreachable.unwrap().span;
+        assert_eq!((span.start.line, span.end.line), (8, 9));
+        assert_eq!(warnings.len(), 2);
+    }
+
+    #[test]
+    fn jumps() {
+        let mut program = compile("- \"a\"\n- \"b\"\n").unwrap();
+        program.byte_code[1] = OpCode::JMP(1);
+        assert_eq!(diagnostics(&program), vec![(2, EndlessJump)]);
+
+        program.byte_code[1] = OpCode::JMP(10);
+        assert_eq!(diagnostics(&program), vec![(2, InvalidJump(10))]);
+    }
+}
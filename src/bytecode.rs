@@ -0,0 +1,492 @@
+use std::collections::HashSet;
+use std::io;
+
+use crate::models::*;
+
+const MAGIC: &[u8; 4] = b"RPGC";
+const VERSION: u8 = 7;
+
+// Layout of a compiled story, integers being little endian u64s -
+// MAGIC, VERSION, byte code, spans, ids, blocks, undo, variables
+impl Program {
+    pub fn is_compiled(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    // The warnings and the tests are left out, they belong to the source
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new(true);
+        writer.header(MAGIC, VERSION);
+
+        writer.number(self.byte_code.len());
+        for op_code in &self.byte_code {
+            writer.op_code(op_code);
+        }
+        for span in &self.spans {
+            writer.span(span);
+        }
+        for id in &self.ids {
+            writer.string(id);
+        }
+
+        writer.number(self.blocks.len());
+        for block in &self.blocks {
+            writer.string(&block.name);
+            writer.span(&block.span);
+            writer.number(block.parameters.len());
+            for parameter in &block.parameters {
+                writer.string(parameter);
+            }
+            writer.number(block.address);
+            writer.number(block.end);
+            writer.bytes.push(block.used as u8);
+            writer.variables(&block.variables);
+        }
+        writer.bytes.push(self.undo as u8);
+        writer.variables(&self.variables);
+
+        writer.bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Program> {
+        let mut reader = Reader::open(bytes, MAGIC, VERSION, "compiled rpg story")?;
+
+        let length = reader.count()?;
+        let byte_code = (0..length).map(|_| reader.op_code()).collect::<io::Result<_>>()?;
+        let spans = (0..length).map(|_| reader.span()).collect::<io::Result<_>>()?;
+        let ids = (0..length).map(|_| reader.string()).collect::<io::Result<_>>()?;
+
+        let mut blocks = Vec::new();
+        for _ in 0..reader.count()? {
+            let name = reader.string()?;
+            let span = reader.span()?;
+            let parameters = (0..reader.count()?)
+                .map(|_| reader.string())
+                .collect::<io::Result<_>>()?;
+            let address = reader.number()?;
+            let end = reader.number()?;
+            let used = reader.byte()? != 0;
+            let variables = reader.variables()?;
+            blocks.push(Block { name, span, parameters, address, end, used, variables });
+        }
+        let undo = reader.byte()? != 0;
+        let variables = reader.variables()?;
+
+        if reader.position != bytes.len() {
+            return Err(invalid("trailing bytes after the compiled story"));
+        }
+
+        let program = Program {
+            byte_code, spans, ids, blocks, undo, variables,
+            tests: vec![],
+            warnings: vec![],
+        };
+        program.check()?;
+        Ok(program)
+    }
+
+    // Compiled stories could be corrupt or hand written, the addresses are checked for the
+    // analysis and the runtime to be able to trust them
+    fn check(&self) -> io::Result<()> {
+        let length = self.byte_code.len();
+        if !matches!(self.byte_code.last(), Some(OpCode::END)) {
+            return Err(invalid("the byte code doesn't end with the end of the story"));
+        }
+        for block in &self.blocks {
+            if block.address >= block.end || block.end > length {
+                return Err(invalid(&format!("block '{}' is outside of the byte code", block.name)));
+            }
+        }
+
+        let entries: HashSet<usize> = self.blocks.iter().map(|block| block.address).collect();
+        let mut op_codes: Vec<&OpCode> = self.byte_code.iter().collect();
+        while let Some(op_code) = op_codes.pop() {
+            match op_code {
+                OpCode::JMP(target) if *target >= length => {
+                    return Err(invalid(&format!("jump to {}, outside of the byte code", target)));
+                },
+                OpCode::CALL(target, _) if !entries.contains(target) => {
+                    return Err(invalid(&format!("call of {}, where no block starts", target)));
+                },
+                OpCode::BRANCH(_, branches) => {
+                    op_codes.extend(branches.iter().map(|branch| &branch.handler));
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+// FNV-1a of the byte code, the spans left out for edits to the layout to keep it
+// Stable across platforms and Rust versions, unlike the std hashers
+pub fn story_hash(byte_code: &[OpCode]) -> u64 {
+    let mut writer = Writer::new(false);
+    for op_code in byte_code {
+        writer.op_code(op_code);
+    }
+
+    writer.bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub(crate) fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub(crate) struct Writer {
+    pub bytes: Vec<u8>,
+    pub spans: bool,    // Leaving the spans out, for them not to matter in a hash
+}
+
+impl Writer {
+    pub fn new(spans: bool) -> Self {
+        Self { bytes: Vec::new(), spans }
+    }
+
+    // Every file starts with its magic, followed by the format version
+    pub fn header(&mut self, magic: &[u8; 4], version: u8) {
+        self.bytes.extend_from_slice(magic);
+        self.bytes.push(version);
+    }
+
+    pub fn number(&mut self, number: usize) {
+        self.bytes.extend_from_slice(&(number as u64).to_le_bytes());
+    }
+
+    pub fn string(&mut self, string: &str) {
+        self.number(string.len());
+        self.bytes.extend_from_slice(string.as_bytes());
+    }
+
+    pub fn position(&mut self, position: &Position) {
+        self.number(position.line);
+        self.number(position.column);
+        self.number(position.offset);
+    }
+
+    pub fn span(&mut self, span: &Span) {
+        if !self.spans {
+            return;
+        }
+        self.position(&span.start);
+        self.position(&span.end);
+    }
+
+    pub fn template(&mut self, template: &Template) {
+        self.span(&template.span);
+        self.number(template.pieces.len());
+        for piece in &template.pieces {
+            match piece {
+                Piece::Literal(literal) => {
+                    self.bytes.push(0);
+                    self.string(literal);
+                },
+                Piece::Reference(identifier, spot) => {
+                    self.bytes.push(1);
+                    self.string(identifier);
+                    self.number(*spot);
+                },
+            }
+        }
+    }
+
+    pub fn variables(&mut self, variables: &[Variable]) {
+        self.number(variables.len());
+        for variable in variables {
+            self.string(&variable.name);
+            self.number(variable.spot);
+            self.number(variable.address);
+        }
+    }
+
+    pub fn op_code(&mut self, op_code: &OpCode) {
+        match op_code {
+            OpCode::NOP => self.bytes.push(0),
+            OpCode::END => self.bytes.push(1),
+            OpCode::JMP(address) => {
+                self.bytes.push(2);
+                self.number(*address);
+            },
+            OpCode::TELL(message) => {
+                self.bytes.push(3);
+                self.template(message);
+            },
+            OpCode::ASK(question, spot) => {
+                self.bytes.push(4);
+                self.template(question);
+                match spot {
+                    Some(spot) => {
+                        self.bytes.push(1);
+                        self.number(*spot);
+                    },
+                    None => self.bytes.push(0),
+                }
+            },
+            OpCode::BRANCH(question, branches) => {
+                self.bytes.push(5);
+                self.template(question);
+                self.number(branches.len());
+                for branch in branches {
+                    self.template(&branch.option);
+                    self.op_code(&branch.handler);
+                }
+            },
+            OpCode::CALL(address, arguments) => {
+                self.bytes.push(6);
+                self.number(*address);
+                self.number(arguments.len());
+                for argument in arguments {
+                    self.number(*argument);
+                }
+            },
+            OpCode::RET => self.bytes.push(7),
+            OpCode::ASSERT(left, right) => {
+                self.bytes.push(8);
+                self.template(left);
+                self.template(right);
+            },
+        }
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    pub bytes: &'a [u8],
+    pub position: usize,
+}
+
+impl<'a> Reader<'a> {
+    // Checks the magic and the format version written by `Writer::header`
+    pub fn open(bytes: &'a [u8], magic: &[u8; 4], version: u8, what: &str) -> io::Result<Self> {
+        if !bytes.starts_with(magic) {
+            return Err(invalid(&format!("not a {}", what)));
+        }
+        let mut reader = Reader { bytes, position: magic.len() };
+        let found = reader.byte()?;
+        if found != version {
+            return Err(invalid(&format!(
+                "{} in format version {}, expected {}", what, found, version
+            )));
+        }
+        Ok(reader)
+    }
+
+    pub fn take(&mut self, length: usize) -> io::Result<&[u8]> {
+        let end = self.position.checked_add(length)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| invalid("unexpected end of the compiled story"))?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn number(&mut self) -> io::Result<usize> {
+        let bytes = self.take(8)?.try_into().unwrap();
+        usize::try_from(u64::from_le_bytes(bytes))
+            .map_err(|_| invalid("number too large for this platform"))
+    }
+
+    // Every counted item takes a byte at least, bounding the allocations
+    pub fn count(&mut self) -> io::Result<usize> {
+        let count = self.number()?;
+        if count > self.bytes.len() - self.position {
+            return Err(invalid("unexpected end of the compiled story"));
+        }
+        Ok(count)
+    }
+
+    pub fn string(&mut self) -> io::Result<String> {
+        let length = self.count()?;
+        String::from_utf8(self.take(length)?.to_vec())
+            .map_err(|_| invalid("string is not valid UTF-8"))
+    }
+
+    fn position(&mut self) -> io::Result<Position> {
+        Ok(Position { line: self.number()?, column: self.number()?, offset: self.number()? })
+    }
+
+    pub fn span(&mut self) -> io::Result<Span> {
+        Ok(Span::new(self.position()?, self.position()?))
+    }
+
+    fn template(&mut self) -> io::Result<Template> {
+        let span = self.span()?;
+        let mut pieces = Vec::new();
+        for _ in 0..self.count()? {
+            pieces.push(match self.byte()? {
+                0 => Piece::Literal(self.string()?),
+                1 => Piece::Reference(self.string()?, self.number()?),
+                _ => return Err(invalid("unknown template piece")),
+            });
+        }
+        Ok(Template { pieces, span })
+    }
+
+    fn variables(&mut self) -> io::Result<Vec<Variable>> {
+        (0..self.count()?)
+            .map(|_| Ok(Variable {
+                name: self.string()?,
+                spot: self.number()?,
+                address: self.number()?,
+            }))
+            .collect()
+    }
+
+    pub fn op_code(&mut self) -> io::Result<OpCode> {
+        Ok(match self.byte()? {
+            0 => OpCode::NOP,
+            1 => OpCode::END,
+            2 => OpCode::JMP(self.number()?),
+            3 => OpCode::TELL(self.template()?),
+            4 => {
+                let question = self.template()?;
+                let spot = match self.byte()? {
+                    0 => None,
+                    _ => Some(self.number()?),
+                };
+                OpCode::ASK(question, spot)
+            },
+            5 => {
+                let question = self.template()?;
+                let mut branches = Vec::new();
+                for _ in 0..self.count()? {
+                    let option = self.template()?;
+                    branches.push(Branch::new(option, self.op_code()?));
+                }
+                OpCode::BRANCH(question, branches)
+            },
+            6 => {
+                let address = self.number()?;
+                let arguments = (0..self.count()?)
+                    .map(|_| self.number())
+                    .collect::<io::Result<_>>()?;
+                OpCode::CALL(address, arguments)
+            },
+            7 => OpCode::RET,
+            8 => OpCode::ASSERT(self.template()?, self.template()?),
+            _ => return Err(invalid("unknown instruction")),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::analysis::analyse;
+    use crate::lang::compile;
+
+    // Every part of the bytes cut short of their end has to be refused
+    pub fn assert_truncations_refused<T>(
+        bytes: &[u8],
+        mut read: impl FnMut(&[u8]) -> io::Result<T>,
+    ) {
+        for length in 0..bytes.len() {
+            assert!(read(&bytes[..length]).is_err(), "truncated at {}", length);
+        }
+    }
+
+    // The bytes with one of them flipped, for every byte and a few flips of each
+    pub fn corruptions(bytes: &[u8]) -> impl Iterator<Item = Vec<u8>> + '_ {
+        (0..bytes.len()).flat_map(move |i| [0x01, 0x80, 0xff].map(|flip| {
+            let mut corrupt = bytes.to_vec();
+            corrupt[i] ^= flip;
+            corrupt
+        }))
+    }
+
+    const STORY: &str = "\
+name := ? \"Name?\"
+greet := (who) { - \"Hi $who$, that's 5$$\" }
+# \"Where to, $name$?\" {
+    \"Cave\" => greet(name)
+    \"Forest\" => {
+        - \"Trees\"
+        assert \"$name$\" == \"Bob\"
+    }
+}
+@last - \"Bye\"
+";
+
+    #[test]
+    fn round_trip() {
+        let program = compile(STORY).unwrap();
+        let bytes = program.to_bytes();
+        let loaded = Program::from_bytes(&bytes).unwrap();
+
+        assert_eq!(loaded.to_bytes(), bytes);
+        assert_eq!(story_hash(&loaded.byte_code), story_hash(&program.byte_code));
+        assert_eq!(loaded.spans, program.spans);
+        assert_eq!(loaded.ids, program.ids);
+        assert_eq!(loaded.variables, program.variables);
+        assert_eq!(loaded.undo, program.undo);
+        assert_eq!(loaded.blocks.len(), program.blocks.len());
+    }
+
+    #[test]
+    fn truncated_input_is_refused() {
+        assert_truncations_refused(&compile(STORY).unwrap().to_bytes(), Program::from_bytes);
+    }
+
+    // Plays the story on with the first answer or option, for a while
+    fn play(program: Program) {
+        let mut game = Game::new(program);
+        for _ in 0..100 {
+            let input = match game.step_within(1000) {
+                Some(Interaction::Message(_)) => Input::Continue,
+                Some(Interaction::Question(_)) => Input::Answer(String::new()),
+                Some(Interaction::Choice(..)) => Input::Choice(0),
+                _ => return,
+            };
+            if game.resume(input).is_err() {
+                return;
+            }
+        }
+    }
+
+    // Whether they're refused or not, corrupt bytes shouldn't panic, nor should the
+    // stories accepted when they're analysed and played
+    #[test]
+    fn corrupt_input_doesnt_panic() {
+        for corrupt in corruptions(&compile(STORY).unwrap().to_bytes()) {
+            if let Ok(program) = Program::from_bytes(&corrupt) {
+                analyse(&program);
+                play(program);
+            }
+        }
+        assert!(Program::from_bytes(b"RPGC").is_err());
+        assert!(Program::from_bytes(b"not a story").is_err());
+    }
+
+    #[test]
+    fn addresses_are_checked() {
+        let program = compile(STORY).unwrap();
+
+        let mut empty = program.clone();
+        (empty.byte_code, empty.spans, empty.ids) = (vec![], vec![], vec![]);
+        assert!(Program::from_bytes(&empty.to_bytes()).is_err());
+
+        let mut outside = program.clone();
+        outside.blocks[0].address = program.byte_code.len() + 10;
+        assert!(Program::from_bytes(&outside.to_bytes()).is_err());
+
+        let mut call = program.clone();
+        let address = call.byte_code.iter().position(|op_code| matches!(op_code, OpCode::TELL(_)));
+        call.byte_code[address.unwrap()] = OpCode::CALL(0, vec![]);
+        assert!(Program::from_bytes(&call.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn return_from_the_top_level_fails() {
+        let mut program = compile("- \"Hi\"\n").unwrap();
+        program.byte_code[0] = OpCode::RET;
+        let mut game = Game::new(Program::from_bytes(&program.to_bytes()).unwrap());
+        let error = "'0' returns without any block being called".to_string();
+        assert_eq!(game.step(), Interaction::Failed(error));
+        assert!(game.resume(Input::Continue).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::models::*;
+
+// Tell, ask or branch option of the story
+struct Point<'a> {
+    kind: &'static str,
+    template: &'a Template,
+}
+
+// Reached and missed points of the story, by kind, followed by every point never reached
+pub fn coverage_summary(program: &Program, coverage: &Coverage, file_name: &str) -> String {
+    let points = points(program);
+    let mut result = format!("{:<10}{:>7}{:>8}{:>9}\n", "", "Total", "Missed", "Cover");
+    let mut missed = Vec::new();
+    for kind in ["tell", "ask", "option", ""] {
+        let all: Vec<&Point> = points.iter()
+            .filter(|point| kind.is_empty() || point.kind == kind)
+            .collect();
+        let unvisited: Vec<&Point> = all.iter().copied()
+            .filter(|point| visits(coverage, point) == 0)
+            .collect();
+        if !kind.is_empty() {
+            missed.extend(unvisited.iter().copied());
+        }
+
+        let cover = match all.len() {
+            0 => "-".to_string(),
+            total => format!("{:.2}%", 100.0 * (total - unvisited.len()) as f64 / total as f64),
+        };
+        let name = if kind.is_empty() { "total".to_string() } else { format!("{}s", kind) };
+        result += &format!("{:<10}{:>7}{:>8}{:>9}\n", name, all.len(), unvisited.len(), cover);
+    }
+
+    if !missed.is_empty() {
+        missed.sort_by_key(|point| position(point));
+        result += "\nNever reached\n";
+        for point in missed {
+            let (line, column) = position(point);
+            result += &format!(
+                "{}:{}:{}: {} \"{}\"\n", file_name, line, column, point.kind, point.template
+            );
+        }
+    }
+    result
+}
+
+// The source with the times each line was reached in front of it, like `llvm-cov show`
+// Lines only partly reached get a `^0` under the points never reached
+pub fn annotate(program: &Program, coverage: &Coverage, source: &str) -> String {
+    let points = points(program);
+    let mut result = String::new();
+    for (i, line) in source.lines().enumerate() {
+        let on_line: Vec<&Point> = points.iter()
+            .filter(|point| point.template.span.start.line == i + 1)
+            .collect();
+        let count = on_line.iter().map(|point| visits(coverage, point)).max();
+        let count = count.map_or(String::new(), |count| count.to_string());
+        result += &format!("{:>7}|{:>5}|{}\n", count, i + 1, line);
+
+        // Marked only when the line was reached otherwise
+        if count == "0" {
+            continue;
+        }
+        let mut markers = String::new();
+        for point in on_line.iter().filter(|point| visits(coverage, point) == 0) {
+            let column = point.template.span.start.column - 1;
+            markers += &" ".repeat(column.saturating_sub(markers.chars().count()));
+            markers += "^0";
+        }
+        if !markers.is_empty() {
+            result += &format!("{:>7}|{:>5}|{}\n", "", "", markers);
+        }
+    }
+    result
+}
+
+// Every point of the story, branch handlers included, in the order of the source
+fn points(program: &Program) -> Vec<Point<'_>> {
+    fn add<'a>(op_code: &'a OpCode, points: &mut Vec<Point<'a>>) {
+        match op_code {
+            OpCode::TELL(template) => points.push(Point { kind: "tell", template }),
+            OpCode::ASK(template, _) => points.push(Point { kind: "ask", template }),
+            OpCode::BRANCH(_, branches) => for branch in branches {
+                points.push(Point { kind: "option", template: &branch.option });
+                add(&branch.handler, points);
+            },
+            _ => (),
+        }
+    }
+
+    let mut points = Vec::new();
+    for op_code in &program.byte_code {
+        add(op_code, &mut points);
+    }
+    points.sort_by_key(|point| position(point));
+    points.dedup_by_key(|point| position(point));
+    points
+}
+
+fn position(point: &Point) -> (usize, usize) {
+    (point.template.span.start.line, point.template.span.start.column)
+}
+
+fn visits(coverage: &Coverage, point: &Point) -> usize {
+    coverage.visits.get(&position(point)).copied().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::compile;
+
+    const STORY: &str = "\
+name := ? \"Name?\"
+# \"Where to, $name$?\" {
+    \"Cave\" => - \"Dark\"
+    \"Forest\" => {
+        - \"Trees\"
+        - \"More $$ trees\"
+    }
+}
+- \"Bye\"
+";
+
+    // Coverage of going into the cave
+    fn played(program: &Program) -> Coverage {
+        let mut game = Game::new(program.clone());
+        game.coverage = Some(Coverage::default());
+        for input in [Input::Answer("Bob".to_string()), Input::Choice(0), Input::Continue] {
+            game.step();
+            game.resume(input).unwrap();
+        }
+        assert_eq!(game.step(), Interaction::Message("Bye".to_string()));
+        game.coverage.unwrap()
+    }
+
+    #[test]
+    fn summary() {
+        let program = compile(STORY).unwrap();
+        assert_eq!(coverage_summary(&program, &played(&program), "game.rpg"), "\
+\x20           Total  Missed    Cover
+tells           4       2   50.00%
+asks            1       0  100.00%
+options         2       1   50.00%
+total           7       3   57.14%
+
+Never reached
+game.rpg:4:5: option \"Forest\"
+game.rpg:5:11: tell \"Trees\"
+game.rpg:6:11: tell \"More $$ trees\"
+");
+    }
+
+    #[test]
+    fn nothing_to_cover() {
+        let program = compile("f := { - \"f\" }\n").unwrap();
+        let summary = coverage_summary(&program, &Coverage::default(), "game.rpg");
+        assert!(summary.contains("asks            0       0        -\n"), "{}", summary);
+        assert!(summary.contains("Never reached\ngame.rpg:1:10: tell \"f\"\n"), "{}", summary);
+    }
+
+    #[test]
+    fn annotated_source() {
+        let program = compile(STORY).unwrap();
+        assert_eq!(annotate(&program, &played(&program), STORY), "\
+\x20     1|    1|name := ? \"Name?\"
+\x20      |    2|# \"Where to, $name$?\" {
+\x20     1|    3|    \"Cave\" => - \"Dark\"
+\x20     0|    4|    \"Forest\" => {
+\x20     0|    5|        - \"Trees\"
+\x20     0|    6|        - \"More $$ trees\"
+\x20      |    7|    }
+\x20      |    8|}
+\x20     1|    9|- \"Bye\"
+");
+    }
+
+    // Points never reached on lines reached otherwise are marked under them
+    #[test]
+    fn partly_reached_lines() {
+        let program = compile(STORY).unwrap();
+        let mut coverage = Coverage::default();
+        coverage.visits.insert((3, 5), 2);
+        let annotated = annotate(&program, &coverage, STORY);
+        assert!(annotated.contains("\
+\x20     2|    3|    \"Cave\" => - \"Dark\"
+\x20      |     |                ^0
+\x20     0|    4|"), "{}", annotated);
+    }
+}
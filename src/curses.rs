@@ -0,0 +1,161 @@
+use pancurses::*;
+use std::{io, thread, time};
+
+use crate::models::{Frontend, Reply};
+
+// Full screen terminal frontend, typing the messages out char by char
+pub struct Curses {
+    pub window: Window,
+}
+
+impl Curses {
+    pub fn new() -> Self {
+        let window = initscr();
+        raw();
+        window.keypad(true);
+        Self { window }
+    }
+}
+
+impl Default for Curses {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Restoring the terminal, once the game is over
+impl Drop for Curses {
+    fn drop(&mut self) {
+        endwin();
+    }
+}
+
+impl Frontend for Curses {
+    fn tell(&mut self, message: &str) -> io::Result<Reply<()>> {
+        tell_info(message, &self.window);
+        self.window.addstr("\n\nPress any key to continue, F5 or Esc for the menu, Ctrl+Z to undo");
+        self.window.refresh();
+
+        curs_set(0);
+        noecho();
+        let key = self.window.getch();
+        echo();
+        curs_set(1);
+
+        Ok(key.as_ref().and_then(special).unwrap_or(Reply::Answer(())))
+    }
+
+    fn ask(&mut self, question: &str) -> io::Result<Reply<String>> {
+        tell_info(question, &self.window);
+
+        self.window.addstr("\n\n>");
+        Ok(scan(&self.window))
+    }
+
+    fn choose(&mut self, question: &str, options: &[String]) -> io::Result<Reply<usize>> {
+        tell_info(question, &self.window);
+        self.window.addstr("\n\n");
+
+        Ok(branch_selection(options, &self.window))
+    }
+}
+
+// Keys opening the pause menu, or stepping back to the last choice
+fn special<T>(key: &Input) -> Option<Reply<T>> {
+    match key {
+        Input::KeyF5 | Input::Character('\u{1b}') => Some(Reply::Pause),
+        Input::Character('\u{1a}') => Some(Reply::Undo), // Ctrl+Z
+        _ => None,
+    }
+}
+
+fn tell_info(info: &str, window: &Window) {
+    window.clear();
+    window.mv(0, 0);
+
+    for letter in info.chars() {
+        window.addch(letter as u32);
+        thread::sleep(time::Duration::from_millis(15));
+        window.refresh();
+    }
+}
+
+fn branch_selection(options: &[String], window: &Window) -> Reply<usize> {
+    curs_set(0);
+    noecho();
+    let mut selection = 0;
+    let y = window.get_cur_y();
+    let x = window.get_cur_x();
+
+    // Looping through all choices in the branch
+    // And rendering them in responce to user
+    loop {
+        window.mv(y, x);
+        for (i, option) in options.iter().enumerate() {
+            if i == selection {
+                window.addstr(format!(">[ {} ]\n", option));
+            } else {
+                window.addstr(format!("   {}  \n", option));
+            }
+        }
+
+        let key = window.getch().unwrap();
+        if let Some(reply) = special(&key) {
+            echo();
+            curs_set(1);
+            return reply;
+        }
+
+        match key {
+            Input::Character('\n') => break, // Enter / Return
+            Input::KeyDown => selection += 1,
+            Input::KeyUp => selection = selection.saturating_sub(1),
+            _ => (),
+        }
+
+        if selection >= options.len() {
+            selection = options.len() - 1;
+        }
+    
+    }
+    echo();
+    curs_set(1);
+    Reply::Answer(selection)
+}
+
+// Simulating stdin
+fn scan(window: &Window) -> Reply<String> {
+    let mut buffer = String::new();
+    noecho();
+    loop {
+        let key = window.getch().unwrap();
+        if let Some(reply) = special(&key) {
+            echo();
+            return reply;
+        }
+
+        match key {
+            // Enter / Return
+            Input::Character('\n') => break,
+
+            Input::KeyBackspace => {
+                if !buffer.is_empty() {
+                    buffer.pop();
+                    window.mv(window.get_cur_y(), window.get_cur_x() - 1);
+                    window.delch();
+                }
+                continue;
+            },
+
+            Input::Character(read) => {
+                window.addch(read);
+                buffer.push(read);
+            },
+
+            _ => (),
+        }
+    }
+
+    echo();
+    Reply::Answer(buffer)
+}
@@ -0,0 +1,363 @@
+use std::fmt;
+use std::io::{self, IsTerminal};
+
+use crate::models::*;
+
+impl ErrorType {
+    pub fn message(&self) -> String {
+        match self {
+            ErrorType::InvalidSyntax => "Invalid Syntax".to_string(),
+            ErrorType::Missing(error) => format!("Missing {}", error),
+            ErrorType::Expected(error) => format!("Expected {}", error),
+            ErrorType::InvalidIdentifier(identifier) => format!(
+                "Identifier '{}' used in StringLiteral without declaration", identifier
+            ),
+            ErrorType::UndeclaredIdentifier(identifier) => format!(
+                "Identifier '{}' used without declaration", identifier
+            ),
+            ErrorType::InvalidJump(address) => format!(
+                "Control flow leaves the program, going to instruction {}", address
+            ),
+            ErrorType::DuplicateLabel(label) => format!(
+                "Label '@{}' is already used", label
+            ),
+            ErrorType::UnusedVariable(identifier) => format!(
+                "Variable '{}' is assigned but never used", identifier
+            ),
+            ErrorType::UnusedBlock(identifier) => format!(
+                "Block '{}' is defined but never called", identifier
+            ),
+            ErrorType::Shadowing(identifier) => format!(
+                "Identifier '{}' shadows an earlier declaration", identifier
+            ),
+            ErrorType::DuplicateOption(option) => format!(
+                "Branch option \"{}\" is repeated", option
+            ),
+            ErrorType::SingleOption => "Branch has a single option".to_string(),
+            ErrorType::UnreachableCode => "Unreachable code".to_string(),
+            ErrorType::UncalledBlock(identifier) => format!(
+                "Block '{}' is never called from the start of the story", identifier
+            ),
+            ErrorType::EndlessBlock(identifier) => format!(
+                "Block '{}' never returns, every path through it loops forever", identifier
+            ),
+            ErrorType::EndlessJump => "Jumps loop forever without any interaction".to_string(),
+        }
+    }
+
+    // Name to silence a warning with, as `// allow(<name>)`
+    pub fn lint_name(&self) -> Option<&'static str> {
+        match self {
+            ErrorType::UnusedVariable(_) => Some("unused-variable"),
+            ErrorType::UnusedBlock(_) => Some("unused-block"),
+            ErrorType::Shadowing(_) => Some("shadowing"),
+            ErrorType::DuplicateOption(_) => Some("duplicate-option"),
+            ErrorType::SingleOption => Some("single-option"),
+            ErrorType::UnreachableCode => Some("unreachable-code"),
+            ErrorType::UncalledBlock(_) => Some("uncalled-block"),
+            ErrorType::EndlessBlock(_) | ErrorType::EndlessJump => Some("infinite-loop"),
+            _ => None,
+        }
+    }
+
+    // Stable identifier of the kind of error, for tools to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorType::InvalidSyntax => "E0001",
+            ErrorType::Missing(_) => "E0002",
+            ErrorType::Expected(_) => "E0003",
+            ErrorType::InvalidIdentifier(_) => "E0004",
+            ErrorType::UndeclaredIdentifier(_) => "E0005",
+            ErrorType::InvalidJump(_) => "E0006",
+            ErrorType::DuplicateLabel(_) => "E0007",
+            ErrorType::UnusedVariable(_) => "W0001",
+            ErrorType::UnusedBlock(_) => "W0002",
+            ErrorType::Shadowing(_) => "W0003",
+            ErrorType::DuplicateOption(_) => "W0004",
+            ErrorType::SingleOption => "W0005",
+            ErrorType::UnreachableCode => "W0006",
+            ErrorType::UncalledBlock(_) => "W0007",
+            ErrorType::EndlessBlock(_) => "W0008",
+            ErrorType::EndlessJump => "W0009",
+        }
+    }
+}
+
+impl fmt::Display for ErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+// `<line>:<column>: <severity>[<code>]: <message>`
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "{}:{}: {}[{}]: {}",
+            self.span.start.line, self.span.start.column,
+            self.severity, self.error_type.code(), self.error_type,
+        )
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Reports all the diagnostics in the given format, returning whether any of them is an error
+pub fn report(
+    diagnostics: &[Error], file_name: &str, source: &str, format: MessageFormat
+) -> bool {
+    match format {
+        MessageFormat::Human => {
+            let colored = io::stderr().is_terminal();
+            for diagnostic in diagnostics {
+                eprintln!("{}", diagnostic.render(file_name, source, colored));
+            }
+
+            let errors = diagnostics.iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                .count();
+            if errors > 1 {
+                eprintln!(
+                    "{}: {}",
+                    Paint(colored).apply("1;31", "error"),
+                    Paint(colored).apply("1", &format!("aborting due to {} previous errors", errors)),
+                );
+            }
+        },
+
+        MessageFormat::Json => for diagnostic in diagnostics {
+            println!("{}", diagnostic.to_json(file_name, source));
+        },
+    }
+
+    diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Error)
+}
+
+impl Error {
+    // Reports the error onto stderr, coloured if it's a terminal, returning whether it's an error
+    pub fn complain(self, file_name: &str, source: &str) -> bool {
+        report(&[self], file_name, source, MessageFormat::Human)
+    }
+
+    // Renders the error the way rustc does -
+    //
+    // error: Expected '>' after '=', for '=>' operator
+    //  --> game.rpg:1:4
+    //   |
+    // 1 | x = y
+    //   |    ^
+    //   |
+    //   = help: did you mean `=>`?
+    pub fn render(&self, file_name: &str, source: &str, colored: bool) -> String {
+        let paint = Paint(colored);
+        let start = &self.span.start;
+        let color = match self.severity {
+            Severity::Error => "1;31",
+            Severity::Warning => "1;33",
+        };
+
+        let gutter = " ".repeat(start.line.to_string().len());
+        let bar = paint.apply("1;34", "|");
+
+        let mut result = format!(
+            "{}: {}\n{}{} {}:{}:{}\n",
+            paint.apply(color, &self.severity.to_string()),
+            paint.apply("1", &self.error_type.message()),
+            gutter, paint.apply("1;34", "-->"),
+            file_name, start.line, start.column,
+        );
+
+        if let Some(line) = source.lines().nth(start.line.wrapping_sub(1)) {
+            // Keeping tabs for the underline to stay aligned with the line
+            let indent: String = line.chars()
+                .take(start.column - 1)
+                .map(|letter| if letter == '\t' { '\t' } else { ' ' })
+                .collect();
+
+            let length = if self.span.end.line == start.line {
+                self.span.end.column.saturating_sub(start.column)
+            } else {
+                line.chars().count().saturating_sub(start.column - 1)
+            };
+
+            result += &format!("{} {}\n", gutter, bar);
+            result += &format!("{} {} {}\n", paint.apply("1;34", &start.line.to_string()), bar, line);
+            result += &format!(
+                "{} {} {}{}\n",
+                gutter, bar, indent, paint.apply(color, &"^".repeat(length.max(1)))
+            );
+        }
+
+        if let Some(help) = &self.help {
+            result += &format!("{} {}\n", gutter, bar);
+            result += &format!("{} {} {}: {}\n", gutter, paint.apply("1;34", "="), paint.apply("1", "help"), help);
+        }
+
+        result
+    }
+
+    // A single line JSON object, for editors and CI annotations
+    pub fn to_json(&self, file_name: &str, source: &str) -> String {
+        let position = |position: &Position| format!(
+            "{{\"line\":{},\"column\":{},\"offset\":{}}}",
+            position.line, position.column, position.offset
+        );
+        let help = match &self.help {
+            Some(help) => json_string(help),
+            None => "null".to_string(),
+        };
+
+        format!(
+            "{{\"file\":{},\"severity\":\"{}\",\"code\":\"{}\",\"message\":{},\"help\":{},\
+\"span\":{{\"start\":{},\"end\":{}}},\"rendered\":{}}}",
+            json_string(file_name),
+            self.severity,
+            self.error_type.code(),
+            json_string(&self.error_type.message()),
+            help,
+            position(&self.span.start),
+            position(&self.span.end),
+            json_string(&self.render(file_name, source, false)),
+        )
+    }
+}
+
+// Quoted and escaped JSON string
+pub(crate) fn json_string(text: &str) -> String {
+    let mut result = String::from('"');
+    for letter in text.chars() {
+        match letter {
+            '"' => result += "\\\"",
+            '\\' => result += "\\\\",
+            '\n' => result += "\\n",
+            '\r' => result += "\\r",
+            '\t' => result += "\\t",
+            letter if (letter as u32) < 0x20 => result += &format!("\\u{:04x}", letter as u32),
+            letter => result.push(letter),
+        }
+    }
+    result.push('"');
+    result
+}
+
+// ANSI colours, applied only when enabled
+struct Paint(bool);
+
+impl Paint {
+    fn apply(&self, code: &str, text: &str) -> String {
+        if self.0 {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ErrorType::*;
+
+    fn span(line: usize, start: usize, end_line: usize, end: usize) -> Span {
+        Span::new(
+            Position { line, column: start, offset: 0 },
+            Position { line: end_line, column: end, offset: 0 },
+        )
+    }
+
+    #[test]
+    fn render_points_at_the_span() {
+        let missing = Missing("'>' after '=', for '=>' operator".to_string());
+        let error = Error::at(missing, span(1, 4, 1, 5)).with_help("did you mean `=>`?");
+        assert_eq!(error.render("game.rpg", "x = y\n", false), "\
+error: Missing '>' after '=', for '=>' operator
+ --> game.rpg:1:4
+  |
+1 | x = y
+  |    ^
+  |
+  = help: did you mean `=>`?
+");
+    }
+
+    #[test]
+    fn render_keeps_tabs_and_wide_gutters() {
+        let source = format!("{}\t- \"$nmae$\"\n", "\n".repeat(9));
+        let error = Error::warning(InvalidIdentifier("nmae".to_string()), span(10, 5, 10, 11));
+        assert_eq!(error.render("game.rpg", &source, false), "\
+warning: Identifier 'nmae' used in StringLiteral without declaration
+  --> game.rpg:10:5
+   |
+10 | \t- \"$nmae$\"
+   | \t   ^^^^^^
+");
+    }
+
+    // Spans over several lines are underlined till the end of the first one
+    #[test]
+    fn render_spans_over_lines() {
+        let error = Error::at(Missing("'}'".to_string()), span(1, 3, 3, 1));
+        let rendered = error.render("game.rpg", "f := {\n    - \"a\"\n", false);
+        assert!(rendered.ends_with("1 | f := {\n  |   ^^^^\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn render_past_the_source() {
+        let error = Error::at(InvalidSyntax, span(5, 1, 5, 1));
+        assert_eq!(error.render("game.rpg", "- \"a\"\n", false), "\
+error: Invalid Syntax
+ --> game.rpg:5:1
+");
+    }
+
+    #[test]
+    fn render_colours() {
+        let error = Error::at(InvalidSyntax, span(1, 1, 1, 2));
+        let rendered = error.render("game.rpg", "x\n", true);
+        assert!(rendered.starts_with("\x1b[1;31merror\x1b[0m: \x1b[1mInvalid Syntax\x1b[0m\n"));
+        assert!(rendered.contains("\x1b[1;31m^\x1b[0m"));
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("a \"b\" \\ c"), r#""a \"b\" \\ c""#);
+        assert_eq!(json_string("1\n2\r3\t4"), r#""1\n2\r3\t4""#);
+        assert_eq!(json_string("\u{1}\u{1f}é"), "\"\\u0001\\u001fé\"");
+    }
+
+    #[test]
+    fn to_json() {
+        let mut error = Error::at(InvalidSyntax, span(1, 1, 1, 2));
+        error.span.end.offset = 1;
+        assert_eq!(error.to_json("a \"b\".rpg", "x\n"), concat!(
+            r#"{"file":"a \"b\".rpg","severity":"error","code":"E0001","#,
+            r#""message":"Invalid Syntax","help":null,"span":{"start":{"line":1,"column":1,"offset":0},"#,
+            r#""end":{"line":1,"column":2,"offset":1}},"#,
+            r#""rendered":"error: Invalid Syntax\n --> a \"b\".rpg:1:1\n  |\n1 | x\n  | ^\n"}"#,
+        ));
+
+        let warning = Error::warning(SingleOption, span(1, 1, 1, 2)).with_help("add \"another\"");
+        let json = warning.to_json("game.rpg", "x\n");
+        assert!(json.contains(r#""severity":"warning","code":"W0005""#), "{}", json);
+        assert!(json.contains(r#""help":"add \"another\"""#), "{}", json);
+    }
+
+    #[test]
+    fn report_tells_whether_there_are_errors() {
+        let warning = Error::warning(SingleOption, span(1, 1, 1, 2));
+        let error = Error::at(InvalidSyntax, span(1, 1, 1, 2));
+        assert!(!report(&[], "game.rpg", "x\n", MessageFormat::Json));
+        assert!(!report(std::slice::from_ref(&warning), "game.rpg", "x\n", MessageFormat::Json));
+        assert!(report(&[warning, error], "game.rpg", "x\n", MessageFormat::Json));
+    }
+}
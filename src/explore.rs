@@ -0,0 +1,329 @@
+use crate::models::*;
+
+// Where the story is, waiting on the player, for telling the paths coming back to it apart
+type State = (usize, Vec<usize>, usize, Vec<(usize, String)>);
+
+// Plays every option of every branch, depth first, with the canned answers to the questions
+// Stories only branch on the options chosen, every answer is tried all the same
+// for the problems depending on them
+pub fn explore(program: Program, options: &ExploreOptions) -> Exploration {
+    let mut explorer = Explorer {
+        options,
+        exploration: Exploration {
+            is_complete: true,
+            ..Exploration::default()
+        },
+        inputs: vec![],
+        visited: vec![],
+    };
+
+    let mut game = Game::new(program);
+    game.undo = false;
+    game.coverage = Some(Coverage::default());
+    if let Outcome::Loops(Some(trap)) = explorer.explore(game, 0) {
+        explorer.report(trap);
+    }
+    explorer.exploration
+}
+
+struct Explorer<'a> {
+    options: &'a ExploreOptions,
+    exploration: Exploration,
+    inputs: Vec<String>,            // Inputs along the path being explored
+    visited: Vec<(State, usize)>,   // States along the path, with the choices made before them
+}
+
+// Where the paths from a point of the story lead
+enum Outcome {
+    Leaves,                 // One of them ends, fails or is cut short by the limits
+    Loops(Option<Problem>), // Every one of them comes back around, along with the first choice
+                            // none of the options lead out of, reported once there's a way
+                            // out before it
+}
+
+impl Explorer<'_> {
+    // Plays the path on, `choices` being the choices with other options made so far
+    fn explore(&mut self, mut game: Game, mut choices: usize) -> Outcome {
+        let (inputs, visited) = (self.inputs.len(), self.visited.len());
+        let outcome = self.path(&mut game, &mut choices);
+        if let Some(coverage) = &game.coverage {
+            self.exploration.coverage.merge(coverage);
+        }
+        self.inputs.truncate(inputs);
+        self.visited.truncate(visited);
+        outcome
+    }
+
+    // The questions and choices the path goes on through are only settled once it's over,
+    // the last input of each being played in place
+    fn path(&mut self, game: &mut Game, choices: &mut usize) -> Outcome {
+        let mut taken = Vec::new();
+        let outcome = self.walk(game, choices, &mut taken);
+        taken.into_iter().rev().fold(outcome, |outcome, (trap, mut outcomes)| {
+            outcomes.push(outcome);
+            self.settle(trap, outcomes)
+        })
+    }
+
+    // `taken` collects the questions and choices along the way, with the outcomes of the
+    // inputs explored on copies of the game
+    fn walk(
+        &mut self, game: &mut Game, choices: &mut usize,
+        taken: &mut Vec<(Option<Problem>, Vec<Outcome>)>
+    ) -> Outcome {
+        loop {
+            if self.exploration.paths >= self.options.path_limit {
+                self.exploration.is_complete = false;
+                return Outcome::Leaves;
+            }
+
+            let interaction = match game.step_within(self.options.step_limit) {
+                Some(interaction) => interaction,
+                None => return self.problem(format!(
+                    "'{}' runs more than {} instructions without waiting on the player, \
+                    it's likely looping forever",
+                    game.program.ids[game.iptr], self.options.step_limit
+                )),
+            };
+
+            let is_choice = matches!(interaction, Interaction::Choice(..));
+            let (question, inputs) = match interaction {
+                Interaction::End => {
+                    self.exploration.paths += 1;
+                    self.exploration.endings += 1;
+                    return Outcome::Leaves;
+                },
+                Interaction::Failed(error) => return self.problem(error),
+                Interaction::Message(_) => (String::new(), vec![]),
+                Interaction::Question(question) => {
+                    let answers = self.options.answers.get(&question)
+                        .unwrap_or(&self.options.default_answers);
+                    let inputs = answers.iter().map(|answer| {
+                        (Input::Answer(answer.clone()), format!("answer \"{}\"", answer))
+                    }).collect();
+                    (question, inputs)
+                },
+                Interaction::Choice(question, options) => {
+                    let inputs = options.iter().enumerate().map(|(selection, option)| {
+                        (Input::Choice(selection), format!("choose \"{}\"", option))
+                    }).collect();
+                    (question, inputs)
+                },
+            };
+
+            // Coming back through a choice is up to the player, without one there's no way out
+            let state = state(game);
+            if let Some(&(_, before)) = self.visited.iter().find(|(other, _)| *other == state) {
+                if before == *choices {
+                    return self.problem(format!(
+                        "'{}' is reached again and again, without any choice leading out of it",
+                        game.program.ids[game.iptr]
+                    ));
+                }
+                self.exploration.paths += 1;
+                self.exploration.loops += 1;
+                return Outcome::Loops(None);
+            }
+            self.visited.push((state, *choices));
+
+            if inputs.is_empty() {
+                if let Err(error) = game.resume(Input::Continue) {
+                    return self.problem(error.to_string());
+                }
+                continue;
+            }
+            if self.inputs.len() >= self.options.depth_limit {
+                return self.problem(format!(
+                    "the path goes on for more than {} questions and choices",
+                    self.options.depth_limit
+                ));
+            }
+            if inputs.len() > 1 && matches!(game.current(), OpCode::BRANCH(..)) {
+                *choices += 1;
+            }
+
+            let trap = is_choice.then(|| Problem {
+                message: format!(
+                    "no option of '{}' leads to an ending, they all come back around",
+                    game.program.ids[game.iptr]
+                ),
+                inputs: self.inputs.clone(),
+                paths: 1,
+            });
+
+            // Every input but the last is explored on a copy of the game,
+            // the copies covering only what's after the copying
+            let last = inputs.len() - 1;
+            let mut outcomes = Vec::new();
+            for (i, (input, text)) in inputs.into_iter().enumerate() {
+                if i < last {
+                    let mut copy = game.clone();
+                    copy.coverage = Some(Coverage::default());
+                    self.inputs.push(text);
+                    outcomes.push(match copy.resume(input) {
+                        Ok(()) => self.explore(copy, *choices),
+                        Err(error) => self.problem(format!("'{}': {}", question, error)),
+                    });
+                    self.inputs.pop();
+                    continue;
+                }
+
+                taken.push((trap, outcomes));
+                self.inputs.push(text);
+                if let Err(error) = game.resume(input) {
+                    return self.problem(format!("'{}': {}", question, error));
+                }
+                break;
+            }
+        }
+    }
+
+    // A choice whose options all come back around can't be left, unless there's a way out
+    // before it, the ones found behind it's options being reported otherwise
+    // Questions are only passed through
+    fn settle(&mut self, trap: Option<Problem>, outcomes: Vec<Outcome>) -> Outcome {
+        if outcomes.iter().all(|outcome| matches!(outcome, Outcome::Loops(_))) {
+            let inner = outcomes.into_iter().find_map(|outcome| match outcome {
+                Outcome::Loops(trap) => trap,
+                Outcome::Leaves => None,
+            });
+            return Outcome::Loops(trap.or(inner));
+        }
+
+        for outcome in outcomes {
+            if let Outcome::Loops(Some(trap)) = outcome {
+                self.report(trap);
+            }
+        }
+        Outcome::Leaves
+    }
+
+    // Ends the path with the problem
+    fn problem(&mut self, message: String) -> Outcome {
+        self.exploration.paths += 1;
+        self.report(Problem {
+            message,
+            inputs: self.inputs.clone(),
+            paths: 1,
+        });
+        Outcome::Leaves
+    }
+
+    // Problems met along many paths are kept once, with the shortest of them
+    fn report(&mut self, problem: Problem) {
+        let problems = &mut self.exploration.problems;
+        match problems.iter_mut().find(|other| other.message == problem.message) {
+            Some(other) => {
+                other.paths += problem.paths;
+                if problem.inputs.len() < other.inputs.len() {
+                    other.inputs = problem.inputs;
+                }
+            },
+            None => problems.push(problem),
+        }
+    }
+}
+
+// The frames below the current one don't matter, calls into the same block
+// again and again coming back to the same state
+fn state(game: &Game) -> State {
+    let frame = game.frame();
+    let mut states: Vec<(usize, String)> = frame.states.clone().into_iter().collect();
+    states.sort();
+    (game.iptr, game.path.clone(), frame.return_address, states)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::lang::compile;
+
+    fn explored(source: &str, options: &ExploreOptions) -> Exploration {
+        explore(compile(source).unwrap(), options)
+    }
+
+    fn messages(exploration: &Exploration) -> Vec<&str> {
+        exploration.problems.iter().map(|problem| problem.message.as_str()).collect()
+    }
+
+    #[test]
+    fn every_option_is_played() {
+        let source = "# \"A?\" {\n    \"1\" => - \"one\"\n    \"2\" => - \"two\"\n}\n\
+            # \"B?\" {\n    \"1\" => - \"one\"\n    \"2\" => - \"two\"\n}\n";
+        let exploration = explored(source, &ExploreOptions::default());
+        assert_eq!((exploration.paths, exploration.endings), (4, 4));
+        assert!(exploration.problems.is_empty() && exploration.is_complete);
+    }
+
+    #[test]
+    fn loop_with_a_way_out() {
+        let source = "f := {\n    # \"Again?\" {\n        \"yes\" => f\n        \"no\" => - \"bye\"\n\
+            }\n}\nf\n";
+        let exploration = explored(source, &ExploreOptions::default());
+        assert_eq!((exploration.endings, exploration.loops), (2, 1));
+        assert!(exploration.problems.is_empty());
+    }
+
+    #[test]
+    fn loop_without_a_way_out() {
+        let source = "f := {\n    # \"Again?\" {\n        \"yes\" => f\n        \"yes!\" => f\n\
+            }\n}\n\
+            # \"Start?\" {\n    \"loop\" => f\n    \"end\" => - \"bye\"\n}\n";
+        let exploration = explored(source, &ExploreOptions::default());
+        assert_eq!(exploration.endings, 1);
+        assert_eq!(messages(&exploration), [
+            "no option of 'f.0' leads to an ending, they all come back around"
+        ]);
+        assert_eq!(exploration.problems[0].inputs, ["choose \"loop\""]);
+    }
+
+    #[test]
+    fn loop_without_a_choice() {
+        let source = "f := {\n    - \"again\"\n    f\n}\nf\n";
+        let exploration = explored(source, &ExploreOptions::default());
+        assert_eq!(messages(&exploration), [
+            "'f.0' is reached again and again, without any choice leading out of it"
+        ]);
+    }
+
+    #[test]
+    fn block_running_forever() {
+        let options = ExploreOptions { step_limit: 100, ..ExploreOptions::default() };
+        let exploration = explored("f := {\n    f\n}\nf\n", &options);
+        assert_eq!(messages(&exploration), [
+            "'f.0' runs more than 100 instructions without waiting on the player, \
+            it's likely looping forever"
+        ]);
+    }
+
+    #[test]
+    fn only_questions_and_choices_count_towards_the_depth() {
+        let source = "- \"a\"\n- \"b\"\n- \"c\"\nx := ? \"X?\"\ny := ? \"Y?\"\n- \"$x$$y$\"\n";
+        let options = ExploreOptions { depth_limit: 2, ..ExploreOptions::default() };
+        assert!(explored(source, &options).problems.is_empty());
+
+        let options = ExploreOptions { depth_limit: 1, ..ExploreOptions::default() };
+        assert_eq!(messages(&explored(source, &options)), [
+            "the path goes on for more than 1 questions and choices"
+        ]);
+    }
+
+    #[test]
+    fn problems_are_kept_once() {
+        let source = "name := ? \"Name?\"\n\
+            # \"A?\" {\n    \"1\" => - \"one\"\n    \"2\" => - \"two\"\n}\n\
+            assert \"$name$\" == \"Bob\"\n";
+        let options = ExploreOptions {
+            answers: HashMap::from([
+                ("Name?".to_string(), vec!["Bob".to_string(), "Eve".to_string()])
+            ]),
+            ..ExploreOptions::default()
+        };
+        let exploration = explored(source, &options);
+        assert_eq!((exploration.paths, exploration.endings), (4, 2));
+        assert_eq!(messages(&exploration), ["6:8: assertion failed, \"Eve\" isn't \"Bob\""]);
+        assert_eq!(exploration.problems[0].paths, 2);
+        assert_eq!(exploration.problems[0].inputs, ["answer \"Eve\"", "choose \"1\""]);
+    }
+}
@@ -0,0 +1,279 @@
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+use crate::lang::compile;
+use crate::models::*;
+use TokenType::*;
+
+const INDENT: &str = "    ";
+
+// Writes the tokens back in the canonical layout, keeping the comments
+struct Formatter<'a> {
+    source: &'a str,
+    tokens: Peekable<IntoIter<Token>>,
+    comments: Peekable<IntoIter<(Span, String)>>,
+    pending: Vec<String>,   // Comments met in the middle of a statement
+    output: String,
+    start: usize,           // Where the current line starts in the output
+    depth: usize,
+    line: usize,            // Source line of the last token or comment written
+    fresh: bool,            // Nothing written yet in the current block
+}
+
+// Formats the story, one statement per line and four spaces per block
+// At most a single blank line is kept between statements
+// Stories with errors aren't formatted, the errors being returned instead
+pub fn format(source: &str) -> Result<String, Vec<Error>> {
+    compile(source)?;
+    let (tokens, comments, _) = Lexer::lex(source);
+
+    let mut formatter = Formatter {
+        source,
+        tokens: tokens.into_iter().peekable(),
+        comments: comments.into_iter().peekable(),
+        pending: vec![],
+        output: String::new(),
+        start: 0,
+        depth: 0,
+        line: 0,
+        fresh: true,
+    };
+
+    while let Some(token) = formatter.tokens.peek() {
+        let start = token.span.start.clone();
+        formatter.line_start(&start);
+        formatter.statement();
+        formatter.newline();
+    }
+    formatter.comments_before(None);
+
+    Ok(formatter.output)
+}
+
+impl Formatter<'_> {
+    // Comments before `position` go on lines of their own
+    fn line_start(&mut self, position: &Position) {
+        self.comments_before(Some(position));
+        self.blank_line(position.line);
+        self.start = self.output.len();
+        self.output += &INDENT.repeat(self.depth);
+        self.fresh = false;
+    }
+
+    fn comments_before(&mut self, position: Option<&Position>) {
+        let is_before = |span: &Span| position.is_none_or(|position| span.start.offset < position.offset);
+        while let Some((span, comment)) = self.comments.next_if(|(span, _)| is_before(span)) {
+            self.blank_line(span.start.line);
+            self.output += &INDENT.repeat(self.depth);
+            self.output += &format!("//{}\n", comment.trim_end());
+            self.line = span.end.line;
+            self.fresh = false;
+        }
+    }
+
+    fn blank_line(&mut self, line: usize) {
+        if line > self.line + 1 && !self.fresh {
+            self.output.push('\n');
+        }
+    }
+
+    // Ends the line, along with the comment trailing it
+    // Comments met in the middle of the statement go on lines of their own above it,
+    // so that an `// allow(...)` among them still covers the statement
+    fn newline(&mut self) {
+        let next = self.tokens.peek().map(|token| token.span.start.offset);
+        while let Some((_, comment)) = self.comments.next_if(|(span, _)| {
+            span.start.line == self.line && next.is_none_or(|next| span.start.offset < next)
+        }) {
+            self.pending.push(comment);
+        }
+
+        let trailing = self.pending.pop();
+        let above: String = self.pending.drain(..).map(|comment| {
+            format!("{}//{}\n", INDENT.repeat(self.depth), comment.trim_end())
+        }).collect();
+        self.output.insert_str(self.start, &above);
+        if let Some(comment) = trailing {
+            self.output += &format!(" //{}", comment.trim_end());
+        }
+        self.output.push('\n');
+    }
+
+    // The source is known to be valid, the tokens are there
+    fn next(&mut self) -> Token {
+        let token = self.tokens.next().unwrap();
+        while let Some((_, comment)) = self.comments.next_if(|(span, _)| {
+            span.start.offset < token.span.start.offset
+        }) {
+            self.pending.push(comment);
+        }
+
+        self.line = token.span.end.line;
+        token
+    }
+
+    fn write(&mut self, token: &Token) {
+        self.output += token.span.slice(self.source);
+    }
+
+    fn peek_is(&mut self, token_type: TokenType) -> bool {
+        self.tokens.peek().is_some_and(|token| token.token_type == token_type)
+    }
+
+    // Statements and expressions alike
+    fn statement(&mut self) {
+        let token = self.next();
+        match token.token_type {
+            TellOp | AskOp => {
+                self.write(&token);
+                self.output.push(' ');
+                let string = self.next();
+                self.write(&string);
+            },
+
+            BranchOp => {
+                self.output += "# ";
+                let question = self.next();
+                self.write(&question);
+                self.output.push(' ');
+                self.next();
+                self.block(Self::option);
+            },
+
+            Identifier(_) => {
+                self.write(&token);
+                if self.peek_is(AssignmentOp) {
+                    self.next();
+                    self.output += " := ";
+                    self.statement();
+                }
+                else if self.peek_is(ParOpen) {
+                    self.next();
+                    self.arguments();
+                }
+                // Keywords, `assert "a" == "b"`, `test "name" { ... }` and it's steps
+                else if self.tokens.peek().is_some_and(|next| matches!(next.token_type, StringLiteral(_))) {
+                    self.output.push(' ');
+                    let string = self.next();
+                    self.write(&string);
+                    if self.peek_is(EqualOp) {
+                        self.next();
+                        self.output += " == ";
+                        let right = self.next();
+                        self.write(&right);
+                    }
+                    else if self.peek_is(BrackOpen) {
+                        self.output.push(' ');
+                        self.next();
+                        self.block(Self::statement);
+                    }
+                }
+            },
+
+            ParOpen => {
+                self.arguments();
+                self.output.push(' ');
+                self.next();
+                self.block(Self::statement);
+            },
+
+            BrackOpen => self.block(Self::statement),
+
+            // Labels stay on the line of their statement
+            Label(_) => {
+                self.write(&token);
+                self.output.push(' ');
+                self.statement();
+            },
+
+            _ => self.write(&token),
+        }
+    }
+
+    // StringLiteral LambdaOp statement
+    fn option(&mut self) {
+        let option = self.next();
+        self.write(&option);
+        self.next();
+        self.output += " => ";
+        self.statement();
+    }
+
+    // `(` being already consumed
+    fn arguments(&mut self) {
+        let mut arguments = Vec::new();
+        loop {
+            let token = self.next();
+            if token.token_type == ParClose {
+                break;
+            }
+            arguments.push(token.span.slice(self.source).to_string());
+        }
+        self.output += &format!("({})", arguments.join(" "));
+    }
+
+    // `{` being already consumed, each item of it on a line of it's own
+    fn block(&mut self, item: fn(&mut Self)) {
+        let close = self.tokens.peek().unwrap().span.start.clone();
+        let is_empty = self.peek_is(BrackClose)
+            && self.comments.peek().is_none_or(|(span, _)| span.start.offset > close.offset);
+        if is_empty {
+            self.next();
+            self.output += "{}";
+            return;
+        }
+
+        self.output.push('{');
+        self.newline();
+        self.depth += 1;
+        self.fresh = true;
+        while !self.peek_is(BrackClose) {
+            let start = self.tokens.peek().unwrap().span.start.clone();
+            self.line_start(&start);
+            item(self);
+            self.newline();
+        }
+
+        let close = self.tokens.peek().unwrap().span.start.clone();
+        self.comments_before(Some(&close));
+        self.depth -= 1;
+        self.start = self.output.len();
+        self.output += &INDENT.repeat(self.depth);
+        self.next();
+        self.output.push('}');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_stay_on_lines_of_their_own() {
+        let source = "\
+# \"Where?\" {
+    \"x\" => // c2
+        - \"y\" // c3
+    \"z\" => - \"w\"
+}
+";
+        let formatted = format(source).unwrap();
+        assert_eq!(formatted, "\
+# \"Where?\" {
+    // c2
+    \"x\" => - \"y\" // c3
+    \"z\" => - \"w\"
+}
+");
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn allowed_warnings_stay_allowed() {
+        let source = "a := // allow(unused-variable)\n    ? \"A\" // c3\n";
+        assert!(compile(source).unwrap().warnings.is_empty());
+        let formatted = format(source).unwrap();
+        assert_eq!(formatted, "// allow(unused-variable)\na := ? \"A\" // c3\n");
+        assert!(compile(&formatted).unwrap().warnings.is_empty());
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::HashSet;
+
+use crate::models::*;
+
+// Longest label text shown on a node or an edge
+const LABEL_LENGTH: usize = 40;
+
+enum Shape {
+    Message,
+    Question,
+    Branch,
+    Call,
+    Return,
+    End,
+}
+
+struct Node {
+    id: String,
+    label: String,
+    position: String,   // `<file>:<line>:<column>` it was compiled from
+    shape: Shape,
+    block: Option<usize>,
+}
+
+struct Edge {
+    from: String,
+    to: String,
+    label: Option<String>,
+    is_call: bool,
+}
+
+// The story as nodes and edges, instead of a flat list of instructions
+struct Graph<'a> {
+    program: &'a Program,
+    file_name: &'a str,
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+// Exports the compiled story as a Graphviz DOT or a Mermaid flowchart
+// Messages, questions and branches are nodes, branch options labelled edges
+// and every named block a subgraph of it's own
+pub fn graph(program: &Program, file_name: &str, format: GraphFormat) -> String {
+    let mut graph = Graph::new(program, file_name);
+    graph.build();
+
+    match format {
+        GraphFormat::Dot => graph.dot(),
+        GraphFormat::Mermaid => graph.mermaid(),
+    }
+}
+
+impl<'a> Graph<'a> {
+    fn new(program: &'a Program, file_name: &'a str) -> Self {
+        Self { program, file_name, nodes: vec![], edges: vec![] }
+    }
+
+    fn build(&mut self) {
+        for address in 0..self.program.byte_code.len() {
+            let op_code = self.program.byte_code[address].clone();
+            let span = self.program.spans[address].clone();
+            self.node(&op_code, address, &span, format!("n{}", address));
+        }
+    }
+
+    // Adds the node for `op_code`, running at `address`, with it's outgoing edges
+    // Branch handlers are nodes too, running at the address of their branch
+    fn node(&mut self, op_code: &OpCode, address: usize, span: &Span, id: String) {
+        let next = self.visible(address + 1);
+        let (shape, label) = match op_code {
+            OpCode::NOP | OpCode::JMP(_) | OpCode::ASSERT(..) => return,
+            OpCode::TELL(message) => (Shape::Message, format!("- \"{}\"", message)),
+            OpCode::ASK(question, _) => (Shape::Question, format!("? \"{}\"", question)),
+            OpCode::BRANCH(question, branches) => {
+                self.push(id.clone(), Shape::Branch, format!("# \"{}\"", question), address, span);
+                for (i, branch) in branches.iter().enumerate() {
+                    let to = match &branch.handler {
+                        OpCode::NOP => next.clone(),
+                        OpCode::JMP(target) => self.visible(*target),
+                        handler => {
+                            let handler_id = format!("{}_{}", id, i);
+                            self.edges.push(Edge {
+                                from: id.clone(),
+                                to: handler_id.clone(),
+                                label: Some(truncate(&branch.option.to_string())),
+                                is_call: false,
+                            });
+                            self.node(handler, address, &branch.option.span, handler_id);
+                            continue;
+                        },
+                    };
+
+                    if let Some(to) = to {
+                        let label = Some(truncate(&branch.option.to_string()));
+                        self.edges.push(Edge { from: id.clone(), to, label, is_call: false });
+                    }
+                }
+                return;
+            },
+            OpCode::CALL(target, _) => {
+                if let Some(to) = self.visible(*target) {
+                    self.edges.push(Edge { from: id.clone(), to, label: None, is_call: true });
+                }
+                let name = self.program.blocks.iter()
+                    .find(|block| block.address == *target)
+                    .map_or("?", |block| block.name.as_str());
+                (Shape::Call, format!("call {}", name))
+            },
+            OpCode::RET => {
+                self.push(id, Shape::Return, "return".to_string(), address, span);
+                return;
+            },
+            OpCode::END => {
+                self.push(id, Shape::End, "end".to_string(), address, span);
+                return;
+            },
+        };
+
+        self.push(id.clone(), shape, label, address, span);
+        if let Some(to) = next {
+            self.edges.push(Edge { from: id, to, label: None, is_call: false });
+        }
+    }
+
+    fn push(&mut self, id: String, shape: Shape, label: String, address: usize, span: &Span) {
+        let start = &span.start;
+        self.nodes.push(Node {
+            id,
+            label: truncate(&label),
+            position: format!("{}:{}:{}", self.file_name, start.line, start.column),
+            shape,
+            block: self.innermost(address),
+        });
+    }
+
+    // The node actually run from `address`, following jumps and no-ops
+    fn visible(&self, mut address: usize) -> Option<String> {
+        let mut visited = HashSet::new();
+        while visited.insert(address) {
+            match self.program.byte_code.get(address)? {
+                OpCode::NOP | OpCode::ASSERT(..) => address += 1,
+                OpCode::JMP(target) => address = *target,
+                _ => return Some(format!("n{}", address)),
+            }
+        }
+        None
+    }
+
+    // The most nested block, whose byte code contains `address`
+    fn innermost(&self, address: usize) -> Option<usize> {
+        self.program.blocks.iter().enumerate()
+            .filter(|(_, block)| (block.address..block.end).contains(&address))
+            .min_by_key(|(_, block)| block.end - block.address)
+            .map(|(block, _)| block)
+    }
+
+    // The block directly enclosing the definition of `block`
+    fn parent(&self, block: usize) -> Option<usize> {
+        let Block { address: start, end, .. } = self.program.blocks[block];
+        self.program.blocks.iter().enumerate()
+            .filter(|&(other, definition)| {
+                other != block && definition.address <= start.saturating_sub(1)
+                    && end <= definition.end
+            })
+            .min_by_key(|(_, definition)| definition.end - definition.address)
+            .map(|(block, _)| block)
+    }
+
+    fn dot(&self) -> String {
+        let mut result = format!("digraph \"{}\" {{\n", dot_escape(self.file_name));
+        result += "    node [fontname=\"monospace\"];\n";
+        self.dot_block(None, 1, &mut result);
+
+        for edge in &self.edges {
+            let mut attributes = Vec::new();
+            if let Some(label) = &edge.label {
+                attributes.push(format!("label=\"{}\"", dot_escape(label)));
+            }
+            if edge.is_call {
+                attributes.push("style=dashed".to_string());
+            }
+
+            result += &format!("    {} -> {}", edge.from, edge.to);
+            if !attributes.is_empty() {
+                result += &format!(" [{}]", attributes.join(", "));
+            }
+            result += ";\n";
+        }
+
+        result += "}\n";
+        result
+    }
+
+    fn dot_block(&self, block: Option<usize>, depth: usize, result: &mut String) {
+        let indent = "    ".repeat(depth);
+        for node in self.nodes.iter().filter(|node| node.block == block) {
+            let shape = match node.shape {
+                Shape::Message => "box",
+                Shape::Question => "parallelogram",
+                Shape::Branch => "diamond",
+                Shape::Call => "box, style=rounded",
+                Shape::Return => "oval",
+                Shape::End => "doublecircle",
+            };
+            *result += &format!(
+                "{}{} [shape={}, label=\"{}\\n{}\", tooltip=\"{}\"];\n",
+                indent, node.id, shape,
+                dot_escape(&node.label), dot_escape(&node.position), dot_escape(&node.position),
+            );
+        }
+
+        for child in (0..self.program.blocks.len()).filter(|&child| self.parent(child) == block) {
+            *result += &format!("{}subgraph cluster_{} {{\n", indent, child);
+            *result += &format!(
+                "{}    label=\"{}\";\n", indent, dot_escape(&self.program.blocks[child].name)
+            );
+            self.dot_block(Some(child), depth + 1, result);
+            *result += &format!("{}}}\n", indent);
+        }
+    }
+
+    fn mermaid(&self) -> String {
+        let mut result = String::from("flowchart TD\n");
+        self.mermaid_block(None, 1, &mut result);
+
+        for edge in &self.edges {
+            let arrow = if edge.is_call { "-.->" } else { "-->" };
+            match &edge.label {
+                Some(label) => result += &format!(
+                    "    {} {}|\"{}\"| {}\n", edge.from, arrow, mermaid_escape(label), edge.to
+                ),
+                None => result += &format!("    {} {} {}\n", edge.from, arrow, edge.to),
+            }
+        }
+
+        result
+    }
+
+    fn mermaid_block(&self, block: Option<usize>, depth: usize, result: &mut String) {
+        let indent = "    ".repeat(depth);
+        for node in self.nodes.iter().filter(|node| node.block == block) {
+            let (open, close) = match node.shape {
+                Shape::Message => ("[", "]"),
+                Shape::Question => ("[/", "/]"),
+                Shape::Branch => ("{", "}"),
+                Shape::Call => ("([", "])"),
+                Shape::Return => ("(", ")"),
+                Shape::End => ("((", "))"),
+            };
+            *result += &format!(
+                "{}{}{}\"{}<br/>{}\"{}\n",
+                indent, node.id, open,
+                mermaid_escape(&node.label), mermaid_escape(&node.position), close,
+            );
+        }
+
+        for child in (0..self.program.blocks.len()).filter(|&child| self.parent(child) == block) {
+            *result += &format!(
+                "{}subgraph b{} [\"{}\"]\n",
+                indent, child, mermaid_escape(&self.program.blocks[child].name)
+            );
+            self.mermaid_block(Some(child), depth + 1, result);
+            *result += &format!("{}end\n", indent);
+        }
+    }
+}
+
+fn truncate(text: &str) -> String {
+    if text.chars().count() <= LABEL_LENGTH {
+        return text.to_string();
+    }
+    let mut result: String = text.chars().take(LABEL_LENGTH - 3).collect();
+    result += "...";
+    result
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Mermaid understands `#<code>;` entities inside quoted labels
+fn mermaid_escape(text: &str) -> String {
+    let mut result = String::new();
+    for letter in text.chars() {
+        match letter {
+            '"' => result += "#quot;",
+            '#' => result += "#35;",
+            '<' => result += "#lt;",
+            '>' => result += "#gt;",
+            letter => result.push(letter),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::compile;
+
+    const STORY: &str = "\
+name := ? \"Name?\"
+greet := (who) {
+    inner := { - \"Hi <you> #1\" }
+    inner
+    - \"$who$\"
+}
+# \"Go?\" {
+    \"Yes\" => greet(name)
+    \"No\" => - \"A very long goodbye, far longer than forty letters\"
+}
+";
+
+    #[test]
+    fn dot() {
+        let dot = graph(&compile(STORY).unwrap(), "g.rpg", GraphFormat::Dot);
+        assert_eq!(dot, r##"digraph "g.rpg" {
+    node [fontname="monospace"];
+    n0 [shape=parallelogram, label="? \"Name?\"\ng.rpg:1:11", tooltip="g.rpg:1:11"];
+    n8 [shape=diamond, label="# \"Go?\"\ng.rpg:7:1", tooltip="g.rpg:7:1"];
+    n8_0 [shape=box, style=rounded, label="call greet\ng.rpg:8:5", tooltip="g.rpg:8:5"];
+    n8_1 [shape=box, label="- \"A very long goodbye, far longer th...\ng.rpg:9:5", tooltip="g.rpg:9:5"];
+    n9 [shape=doublecircle, label="end\ng.rpg:10:1", tooltip="g.rpg:10:1"];
+    subgraph cluster_0 {
+        label="greet";
+        n5 [shape=box, style=rounded, label="call inner\ng.rpg:4:5", tooltip="g.rpg:4:5"];
+        n6 [shape=box, label="- \"$who$\"\ng.rpg:5:7", tooltip="g.rpg:5:7"];
+        n7 [shape=oval, label="return\ng.rpg:6:1", tooltip="g.rpg:6:1"];
+        subgraph cluster_1 {
+            label="inner";
+            n3 [shape=box, label="- \"Hi <you> #1\"\ng.rpg:3:18", tooltip="g.rpg:3:18"];
+            n4 [shape=oval, label="return\ng.rpg:3:32", tooltip="g.rpg:3:32"];
+        }
+    }
+    n0 -> n8;
+    n3 -> n4;
+    n5 -> n3 [style=dashed];
+    n5 -> n6;
+    n6 -> n7;
+    n8 -> n8_0 [label="Yes"];
+    n8_0 -> n5 [style=dashed];
+    n8_0 -> n9;
+    n8 -> n8_1 [label="No"];
+    n8_1 -> n9;
+}
+"##);
+    }
+
+    #[test]
+    fn mermaid() {
+        let mermaid = graph(&compile(STORY).unwrap(), "g.rpg", GraphFormat::Mermaid);
+        let question = "    n0[/\"? #quot;Name?#quot;<br/>g.rpg:1:11\"/]\n";
+        assert!(mermaid.starts_with(&format!("flowchart TD\n{}", question)));
+        assert!(mermaid.contains("    n8{\"#35; #quot;Go?#quot;<br/>g.rpg:7:1\"}\n"));
+        assert!(mermaid.contains("\
+    subgraph b0 [\"greet\"]
+        n5([\"call inner<br/>g.rpg:4:5\"])
+        n6[\"- #quot;$who$#quot;<br/>g.rpg:5:7\"]
+        n7(\"return<br/>g.rpg:6:1\")
+        subgraph b1 [\"inner\"]
+            n3[\"- #quot;Hi #lt;you#gt; #35;1#quot;<br/>g.rpg:3:18\"]
+"));
+        assert!(mermaid.contains("    n8 -->|\"Yes\"| n8_0\n    n8_0 -.-> n5\n"));
+    }
+
+    // Jumps and no-ops are skipped over, edges going to what actually runs next
+    #[test]
+    fn jumps_are_followed() {
+        let story = "\
+# \"Again?\" {
+    \"Yes\" => {
+        - \"Once\"
+        - \"More\"
+    }
+    \"No\" => { }
+}
+- \"Bye\"
+";
+        let dot = graph(&compile(story).unwrap(), "g.rpg", GraphFormat::Dot);
+        let edges: Vec<&str> = dot.lines().filter(|line| line.contains("->")).collect();
+        assert_eq!(edges, vec![
+            "    n0 -> n2 [label=\"Yes\"];",
+            "    n0 -> n5 [label=\"No\"];",
+            "    n2 -> n3;",
+            "    n3 -> n5;",
+            "    n5 -> n6;",
+        ]);
+    }
+}
@@ -1,28 +1,28 @@
 use std::{fmt::Write, vec, collections::HashMap};
 
 use crate::models::*;
+use crate::analysis::analyse;
 use TokenType::*;
 use ErrorType::*;
 
 // The tokenizer
-impl Lexer {
-    fn lex(source: String) -> Result<Vec<Token>, Error> {
+// Walks the source once through a char iterator, keeping track of the position
+impl<'a> Lexer<'a> {
+    // Errors are collected, the operator being complained about is still produced
+    // Comments are kept aside from the tokens
+    pub(crate) fn lex(source: &'a str) -> (Vec<Token>, Vec<(Span, String)>, Vec<Error>) {
         let mut tokens: Vec<Token> = Vec::new();
-        
+
         // Global variables for different parts of the lexer
         let mut lexer = Lexer {
-            source,
-            index: 0,
-            line: 1,
-            column: 1,
+            chars: source.chars().peekable(),
+            position: Position { line: 1, column: 1, offset: 0 },
+            comments: vec![],
+            errors: vec![],
         };
 
         // Iterates through each char in the rpg_source
         loop {
-            if lexer.index >= lexer.source.len() {
-                break;
-            }
-
             // AskOp,                  // ?
             // TellOp,                 // -
             // ParOpen,                // (
@@ -34,192 +34,187 @@ impl Lexer {
             // AssignmentOp,           // :=
             // Identifier(String),     // <a-zA-Z0-9>
             // StringLiteral(String),  // ""
+            // Label(String),          // @<a-zA-Z0-9>
+            // EqualOp,                // ==
+            // Comment                 // //
+
+            let position = lexer.position();
+            let current_char = if let Some(letter) = lexer.bump() {
+                letter
+            } else { break };
+
+            let token_type = match current_char {
+                '?'  => AskOp,
+                '-'  => TellOp,
+                '('  => ParOpen,
+                ')'  => ParClose,
+                '#'  => BranchOp,
+                '='  if lexer.bump_if('=') => EqualOp,
+                '='  => lexer.lex_lambda_op(&position),
+                '{'  => BrackOpen,
+                '}'  => BrackClose,
+                ':'  => lexer.lex_assignment_op(&position),
+
+                'a'..='z' | 'A'..='Z' | '0'..='9' => lexer.lex_identifier(current_char),
+
+                '"'  => lexer.lex_string_literal(&position),
+
+                '@'  => lexer.lex_label(&position),
+
+                ' ' | '\t' | '\r' | '\n' => continue, // Ignoring white spaces
+
+                '/' if lexer.bump_if('/') => {
+                    lexer.lex_comment(position);
+                    continue;
+                },
 
-            let current_char = lexer.source.chars().nth(lexer.index).unwrap();
-            let position = Position { line: lexer.line, column: lexer.column };
-            match current_char {
-                '?'  => tokens.push(Token{
-                    position, token_type: AskOp,
-                }),
-
-                '-'  => tokens.push(Token{
-                    position, token_type: TellOp,
-                }),
-
-                '('  => tokens.push(Token{
-                    position, token_type: ParOpen,
-                }),
-
-                ')'  => tokens.push(Token{
-                    position, token_type: ParClose,
-                }),
-
-                '#'  => tokens.push(Token{
-                    position, token_type: BranchOp,
-                }),
-
-                '='  => tokens.push(lexer.lex_lambda_op()?),
-
-                '{'  => tokens.push(Token{
-                    position, token_type: BrackOpen,
-                }),
-
-                '}'  => tokens.push(Token{
-                    position, token_type: BrackClose,
-                }),
+                _ => {
+                    // TODO: InvalidChar Error
+                    let error = Error::at(
+                        InvalidSyntax, Span::new(position, lexer.position())
+                    ).with_help(format!(
+                        "'{}' isn't allowed outside of a StringLiteral", current_char
+                    ));
+                    lexer.errors.push(error);
+                    continue;
+                },
+            };
 
-                ':'  => tokens.push(lexer.lex_assignment_op()?),
-                
-                'a'..='z' | 'A'..='Z' | '0'..='9' => tokens.push(lexer.lex_identifier()?),
+            tokens.push(Token {
+                span: Span::new(position, lexer.position()),
+                token_type,
+            });
+        }
 
-                '"'  => tokens.push(lexer.lex_string_literal()?),
+        (tokens, lexer.comments, lexer.errors)
+    }
 
-                '\n' => {
-                    lexer.line += 1;
-                    lexer.column = 0;
-                },
+    fn position(&self) -> Position {
+        self.position.clone()
+    }
 
-                ' ' | '\t' => (), // Ignoring white spaces
+    // Consumes the next char, keeping track of line, column for error messages
+    fn bump(&mut self) -> Option<char> {
+        let letter = self.chars.next()?;
+        self.position.advance(letter);
+        Some(letter)
+    }
 
-                _ => return Err(Error::lex_error(
-                    // TODO: InvalidChar Error
-                    InvalidSyntax, &lexer
-                ))
+    // Consumes the next char only if it is `expected`
+    fn bump_if(&mut self, expected: char) -> bool {
+        if let Some(&letter) = self.chars.peek() {
+            if letter == expected {
+                self.bump();
+                return true;
             }
-            lexer.index += 1;
-            lexer.column += 1;
         }
-
-        Ok(tokens)
+        false
     }
 
     // `=>`
-    fn lex_lambda_op(&mut self) -> Result<Token, Error> {
-        self.index += 1;
-        self.column += 1;
-        if self.index >= self.source.len() {
-            // For error to point at the right location
-            self.index -= 1;
-            self.column -= 1;
-
-            return Err(Error::lex_error(
+    fn lex_lambda_op(&mut self, position: &Position) -> TokenType {
+        if self.chars.peek().is_none() {
+            let error = Error::at(
                 Missing("'>' after '=', for '=>' operator".to_string()),
-                self
-            ))
+                Span::new(position.clone(), self.position())
+            ).with_help("did you mean `=>`?");
+            self.errors.push(error);
         }
 
-        if self.source.chars().nth(self.index).unwrap() != '>' {
-            return Err(Error::lex_error(
+        else if !self.bump_if('>') {
+            let error = Error::lex_error(
                 Expected("'>' after '=', for '=>' operator".to_string()),
                 self
-            ))
+            ).with_help("did you mean `=>`?");
+            self.errors.push(error);
         }
 
-        Ok(Token {
-            position: Position { line: self.line, column: self.column - 1 },
-            token_type: LambdaOp,
-        })
+        LambdaOp
     }
 
     // `:=`
-    fn lex_assignment_op(&mut self) -> Result<Token, Error> {
-        self.index += 1;
-        self.column += 1;
-        if self.index >= self.source.len() {
-            // For error to point at the right location
-            self.index -= 1;
-            self.column -= 1;
-
-            return Err(Error::lex_error(
+    fn lex_assignment_op(&mut self, position: &Position) -> TokenType {
+        if self.chars.peek().is_none() {
+            let error = Error::at(
                 Missing("'=' after ':', for AssignmentOp (':=')".to_string()),
-                self
-            ))
+                Span::new(position.clone(), self.position())
+            ).with_help("did you mean `:=`?");
+            self.errors.push(error);
         }
 
-        if self.source.chars().nth(self.index).unwrap() != '=' {
-            return Err(Error::lex_error(
+        else if !self.bump_if('=') {
+            let error = Error::lex_error(
                 Expected("'=' after ':', for AssignmentOp (':=')".to_string()),
                 self
-            ))
+            ).with_help("did you mean `:=`?");
+            self.errors.push(error);
         }
 
-        Ok(Token {
-            position: Position { line: self.line, column: self.column - 1 },
-            token_type: AssignmentOp,
-        })
+        AssignmentOp
     }
 
     // `*<a-zA-Z0-9>`
-    fn lex_identifier(&mut self) -> Result<Token, Error> {
-        let start_column = self.column;
-        let identifier = String::new();
-
-        let mut letter = self.source.chars().nth(self.index).unwrap();
-        while
-            ('a'..='z').contains(&letter) |
-            ('A'..='Z').contains(&letter) |
-            ('0'..='9').contains(&letter)
-        {
-            identifier.write_char(letter).unwrap();
+    fn lex_identifier(&mut self, first: char) -> TokenType {
+        let mut identifier = String::from(first);
 
-            self.index += 1;
-            self.column += 1;
-            if self.index >= self.source.len() { break; }
-            let mut letter = self.source.chars().nth(self.index).unwrap();
+        while let Some(letter) = self.chars.next_if(char::is_ascii_alphanumeric) {
+            self.position.advance(letter);
+            identifier.push(letter);
         }
-        Ok(Token {
-            position: Position { line: self.line, column: start_column},
-            token_type: Identifier(identifier),
-        })
+
+        Identifier(identifier)
     }
 
-    // `"*<.-">"`
-    fn lex_string_literal(&mut self) -> Result<Token, Error> {
-        let start_line = self.line;
-        let start_column = self.column;
+    // `@+<a-zA-Z0-9>`
+    fn lex_label(&mut self, position: &Position) -> TokenType {
+        let mut label = String::new();
+        while let Some(letter) = self.chars.next_if(char::is_ascii_alphanumeric) {
+            self.position.advance(letter);
+            label.push(letter);
+        }
 
-        self.index += 1;
-        self.column += 1;
-        let mut result = String::new();
+        if label.is_empty() {
+            let error = Error::at(
+                Expected("name of the label after '@'".to_string()),
+                Span::new(position.clone(), self.position())
+            ).with_help("labels are written as `@name`, before a statement");
+            self.errors.push(error);
+        }
 
-        if self.index >= self.source.len() {
-            // For error to point at the right location
-            self.index -= 1;
-            self.column -= 1;
-            return Err(Error::lex_error(
-                Missing("end of '\"' (String literal)".to_string()),
-                self
-            ))
+        Label(label)
+    }
+
+    // `//*<.-\n>`
+    fn lex_comment(&mut self, position: Position) {
+        let mut comment = String::new();
+        while let Some(letter) = self.chars.next_if(|&letter| letter != '\n') {
+            self.position.advance(letter);
+            comment.push(letter);
         }
 
-        let mut letter = self.source.chars().nth(self.index).unwrap();
-        while letter != '"' {
-            // Keeping track of line, column for error messages
-            if letter == '\n' {
-                self.line += 1;
-                self.column = 0;
-            }
+        self.comments.push((Span::new(position, self.position()), comment));
+    }
 
-            result.write_char(letter).unwrap();
+    // `"*<.-">"`
+    fn lex_string_literal(&mut self, position: &Position) -> TokenType {
+        let mut result = String::new();
 
-            self.index += 1;
-            self.column += 1;
-            if self.index >= self.source.len() {
-                self.line = start_line;
-                self.column = start_column;
-                return Err(Error::lex_error(
-                    Missing("This '\"' (String literal) have no ending".to_string()),
-                    self
-                ))
+        loop {
+            match self.bump() {
+                Some('"') => break,
+                Some(letter) => result.push(letter),
+                None => {
+                    let error = Error::at(
+                        Missing("This '\"' (String literal) have no ending".to_string()),
+                        Span::new(position.clone(), self.position())
+                    );
+                    self.errors.push(error);
+                    break;
+                },
             }
-
-            letter = self.source.chars().nth(self.index).unwrap();
         }
 
-        Ok(Token {
-            position: Position { line: start_line, column: start_column },
-            token_type: StringLiteral(result),
-        })
+        StringLiteral(result)
     }
 }
 
@@ -233,324 +228,1188 @@ enum ImmediateType {
     Value,
 }
 
-// TODO: Implement parser according to the new grammar
 impl Parser {
-    fn new(tokens: ParseableTokens) -> Self {
+    fn new(tokens: ParseableTokens, debug: bool) -> Self {
         // Global variables for different parts of the Parser
         Self {
             tokens,
             byte_code: vec![],
+            spans: vec![],
+            ids: vec![],
+            scope: String::new(),
+            index: 0,
+            node: String::new(),
+            emitted: 0,
+            labels: HashMap::new(),
+            variables: vec![],
+            tests: vec![],
+            debug,
+            in_test: false,
+            tested: vec![],
             curr_token: Token {
-                position: Position { line: 0, column: 0 },
+                span: Span::default(),
                 token_type: BrackOpen,
             },
+            value_count: 0,
+            values: vec![],
+            blocks: vec![],
+            defining: vec![],
+            errors: vec![],
+            warnings: vec![],
             value_identifiers: HashMap::new(),
             block_identifiers: HashMap::new(),
         }
     }
 
-    fn parse(tokens: Vec<Token>) -> Result<Vec<OpCode>, Error> {
-        let mut parser = Parser::new(tokens.into_iter().peekable());
+    // The byte code is only meaningful if there are no errors
+    fn parse(tokens: Vec<Token>, debug: bool) -> (Program, Vec<Error>) {
+        let mut parser = Parser::new(tokens.into_iter().peekable(), debug);
 
-        if let Some(token) = parser.tokens.next() {
-            parser.curr_token = token;
-            parser.source()?;
+        parser.source();
+        if let Err(error) = parser.entry() {
+            parser.errors.push(error);
         }
+        let span = parser.curr_token.span.clone();
+        parser.node("$end".to_string());
+        parser.emit(OpCode::END, span);
+        parser.unused();
+        parser.tested();
+
+        let program = Program {
+            byte_code: parser.byte_code,
+            spans: parser.spans,
+            ids: parser.ids,
+            blocks: parser.blocks,
+            undo: true,
+            variables: parser.variables,
+            tests: parser.tests,
+            warnings: parser.warnings,
+        };
+        (program, parser.errors)
+    }
 
-        Ok(parser.byte_code)
+    // Instructions are always pushed along with where they came from
+    // The first one of a statement is named after it, the rest as `<node>+<n>`
+    fn emit(&mut self, op_code: OpCode, span: Span) {
+        let id = match self.emitted {
+            0 => self.node.clone(),
+            n => format!("{}+{}", self.node, n),
+        };
+        self.emitted += 1;
+
+        self.byte_code.push(op_code);
+        self.spans.push(span);
+        self.ids.push(id);
     }
 
-    fn source(&mut self) -> Result<(), Error> {
-        loop {
-            if let Some(statement_token) = self.tokens.next() {
-                self.curr_token = statement_token;
-                self.statement()?;
+    // Handlers are taken back out of the byte code
+    fn unemit(&mut self) -> OpCode {
+        self.spans.pop();
+        self.ids.pop();
+        self.byte_code.pop().unwrap()
+    }
+
+    fn node(&mut self, node: String) {
+        self.node = node;
+        self.emitted = 0;
+    }
+
+    // The statements parsed by `f` are named `<scope>.<index>` onwards
+    // The statement being parsed is carried on after it
+    fn nested(
+        &mut self, scope: String, index: usize, f: impl FnOnce(&mut Self) -> Result<(), Error>
+    ) -> Result<(), Error> {
+        let scope = std::mem::replace(&mut self.scope, scope);
+        let index = std::mem::replace(&mut self.index, index);
+        let (node, emitted) = (self.node.clone(), self.emitted);
+
+        let result = f(self);
+
+        self.scope = scope;
+        self.index = index;
+        self.node = node;
+        self.emitted = emitted;
+        result
+    }
+
+    // Statements are named after their place in the enclosing one, `@label`s naming them explicitly
+    fn label(&mut self, label: String) -> Result<(), Error> {
+        let span = self.curr_token.span.clone();
+        if let Some(earlier) = self.labels.get(&label) {
+            return Err(Error::at(DuplicateLabel(label), span)
+                .with_help(format!("used earlier at line {}", earlier.start.line)));
+        }
+
+        self.labels.insert(label.clone(), span);
+        self.node(label);
+        self.expect_if(|x| *x != BrackClose, "statement after the label")
+    }
+
+    // *statement
+    fn source(&mut self) {
+        while self.advance() {
+            if let Err(error) = self.statement() {
+                self.recover(error);
             }
-            else {
-                break;
+        }
+    }
+
+    // Running `main` after the top level statements, if it's defined
+    fn entry(&mut self) -> Result<(), Error> {
+        if let Some(&main) = self.block_identifiers.get("main") {
+            self.blocks[main].used = true;
+            if !self.blocks[main].parameters.is_empty() {
+                return Err(Error::parse_error(
+                    Expected("'main' block to take no arguments".to_string()),
+                    self
+                ))
             }
+            let address = self.blocks[main].address;
+            let span = self.blocks[main].span.clone();
+            self.node("$entry".to_string());
+            self.emit(OpCode::CALL(address, vec![]), span);
         }
 
         Ok(())
-
     }
 
+    // expr
+    // tell
+    // branch
+    // Identifier AssignmentOp expr
     fn statement(&mut self) -> Result<(), Error> {
+        let node = match self.scope.as_str() {
+            "" => self.index.to_string(),
+            scope => format!("{}.{}", scope, self.index),
+        };
+        self.index += 1;
+        self.node(node);
+
+        let is_labelled = match self.curr_token.token_type.clone() {
+            TokenType::Label(label) => {
+                self.label(label)?;
+                true
+            },
+            _ => false,
+        };
+
         if let TokenType::TellOp = self.curr_token.token_type {
-            self.tell(usize::MAX)?;
-            return Ok(());
+            return self.tell();
+        }
+
+        // `assert` and `test` are only keywords before a StringLiteral
+        if let TokenType::Identifier(keyword) = &self.curr_token.token_type {
+            let is_string_next = self.tokens.peek()
+                .is_some_and(|token| matches!(token.token_type, StringLiteral(_)));
+            match keyword.as_str() {
+                "assert" if is_string_next => return self.assert(),
+                "test" if is_string_next => return self.test(),
+                _ => (),
+            }
         }
         if let TokenType::BranchOp = self.curr_token.token_type {
-            self.branch()?;
-            return Ok(());
+            return self.branch();
         }
 
-        if let TokenType::Identifier(identifier) = self.curr_token.token_type {
-            if let Some(_) = self.tokens.next_if(|x| x.token_type == AssignmentOp) {
-                if let Some(statement_token) = self.tokens.next() {
-                    self.curr_token = statement_token;
+        if let TokenType::Identifier(identifier) = self.curr_token.token_type.clone() {
+            if self.tokens.next_if(|x| x.token_type == AssignmentOp).is_some() {
+                let span = self.curr_token.span.clone();
+                self.expect_if(|x| *x != BrackClose, "expression for variable assignment")?;
+                self.shadowing(&identifier, &span);
+
+                let value_spot = self.value_count;
+                let block_spot = self.blocks.len();
+
+                // Declaring blocks before their body, for them to be able to call themselves
+                // They are named after the block, rather than their place
+                let mut declared = None;
+                if let ParOpen | BrackOpen = self.curr_token.token_type {
+                    declared = Some(self.block_identifiers.insert(identifier.clone(), block_spot));
+                    if !is_labelled {
+                        let node = match self.scope.as_str() {
+                            "" => identifier.clone(),
+                            scope => format!("{}.{}", scope, identifier),
+                        };
+                        self.node(node);
+                    }
+                }
 
-                    let value_spot = self.value_identifiers.len();
-                    let block_spot = self.block_identifiers.len();
-                    match self.expr(value_spot, block_spot)? {
-                        ExprType::Immediate(immediate) => match immediate {
-                            ImmediateType::Value => {
-                                self.value_identifiers.insert(identifier, value_spot);
-                            },
-                            ImmediateType::Block => {
-                                self.block_identifiers.insert(identifier, block_spot);
-                            }
-                        }
+                let expr = self.expr(Some((value_spot, block_spot)));
 
-                        ExprType::Reference(original, original_type) => match original_type {
-                            ImmediateType::Value => {
-                                self.value_identifiers.insert(identifier, original);
+                // Blocks failing before they were pushed, like on their parameters,
+                // can't be referred to, the earlier declaration stays in place
+                if let (Err(_), Some(previous)) = (&expr, declared) {
+                    if self.blocks.len() <= block_spot {
+                        match previous {
+                            Some(previous) => {
+                                self.block_identifiers.insert(identifier.clone(), previous);
+                            },
+                            None => {
+                                self.block_identifiers.remove(&identifier);
                             },
-                            ImmediateType::Block => {
-                                self.block_identifiers.insert(identifier, original);
-                            }
                         }
-                    };
-                    return Ok(());
-                }
-                else {
-                    return Err(Error::parse_error(
-                        Missing("expression for variable assignment".to_string()),
-                        self
-                    ));
+                    }
                 }
+
+                match expr? {
+                    ExprType::Immediate(immediate) => match immediate {
+                        ImmediateType::Value => {
+                            self.value_count += 1;
+                            self.values.push(Value {
+                                name: identifier.clone(),
+                                span,
+                                spot: value_spot,
+                                used: false,
+                                is_parameter: false,
+                            });
+                            let value = self.values.len() - 1;
+                            self.value_identifiers.insert(identifier.clone(), value);
+                            self.variable(identifier, value_spot);
+                        },
+                        ImmediateType::Block => {
+                            self.blocks[block_spot].name = identifier.clone();
+                            self.blocks[block_spot].span = span;
+                            self.block_identifiers.insert(identifier, block_spot);
+                        }
+                    }
+
+                    ExprType::Reference(original, original_type) => match original_type {
+                        ImmediateType::Value => {
+                            self.value_identifiers.insert(identifier.clone(), original);
+                            self.variable(identifier, self.values[original].spot);
+                        },
+                        ImmediateType::Block => {
+                            self.block_identifiers.insert(identifier, original);
+                        }
+                    }
+                };
+                return Ok(());
             }
         }
 
-        self.expr(usize::MAX, usize::MAX)?;
+        self.expr(None)?;
         Ok(())
     }
 
-    fn expr(&mut self, value_spot: usize, block_spot: usize) -> Result<ExprType, Error> {
-        if let TokenType::TellOp = self.curr_token.token_type {
-            self.tell(value_spot)?;
-            return Ok(ExprType::Immediate(ImmediateType::Value));
+    // ask
+    // block
+    // Identifier
+    // `spots` be the (value, block) spots to assign into, `None` for a plain statement
+    fn expr(&mut self, spots: Option<(usize, usize)>) -> Result<ExprType, Error> {
+        match self.curr_token.token_type.clone() {
+            AskOp => {
+                self.ask(spots.map(|(value_spot, _)| value_spot))?;
+                Ok(ExprType::Immediate(ImmediateType::Value))
+            },
+
+            ParOpen | BrackOpen => {
+                self.block(spots.is_some())?;
+                Ok(ExprType::Immediate(ImmediateType::Block))
+            },
+
+            Identifier(identifier) => self.identifier(identifier, spots.is_some()),
+
+            _ => Err(Error::parse_error(
+                Expected("statement".to_string()),
+                self
+            )),
         }
+    }
+
+    // AskOp StringLiteral
+    fn ask(&mut self, value_spot: Option<usize>) -> Result<(), Error> {
+        let question = self.expect_string("question (StringLiteral) after '?'")?;
+
+        let span = question.span.clone();
+        self.emit(OpCode::ASK(question, value_spot), span);
+        Ok(())
+    }
+
+    // TellOp StringLiteral
+    fn tell(&mut self) -> Result<(), Error> {
+        let message = self.expect_string("message (StringLiteral) after '-'")?;
 
-        if let TokenType::Identifier(identifier) = self.curr_token.token_type {
-        
+        let span = message.span.clone();
+        self.emit(OpCode::TELL(message), span);
+        Ok(())
+    }
+
+    // assert StringLiteral EqualOp StringLiteral
+    // Only compiled into debug builds
+    fn assert(&mut self) -> Result<(), Error> {
+        let (left, right) = self.comparison()?;
+        if self.debug {
+            let span = left.span.to(&right.span);
+            self.emit(OpCode::ASSERT(left, right), span);
         }
+        Ok(())
+    }
 
-        todo!()
+    // StringLiteral EqualOp StringLiteral
+    fn comparison(&mut self) -> Result<(Template, Template), Error> {
+        let left = self.expect_string("StringLiteral after `assert`")?;
+        self.expect_if(|x| *x == EqualOp, "'==' (Comparison)")?;
+        let right = self.expect_string("StringLiteral to compare with")?;
+        Ok((left, right))
     }
 
-    fn ask() {
-        todo!()
+    // test StringLiteral BrackOpen *step BrackClose
+    // Left out of the byte code, the steps are kept along with it instead
+    fn test(&mut self) -> Result<(), Error> {
+        let start = self.curr_token.span.clone();
+        self.expect_if(|x| matches!(x, StringLiteral(_)), "name of the test (StringLiteral)")?;
+        let name = match self.curr_token.token_type.clone() {
+            StringLiteral(name) => name,
+            _ => unreachable!(),
+        };
+        let span = start.to(&self.curr_token.span);
+        self.expect_if(|x| *x == BrackOpen, "'{' (Test starting)")?;
+
+        self.in_test = true;
+        let steps = self.steps();
+        self.in_test = false;
+
+        self.tests.push(Test { name, span, steps: steps? });
+        Ok(())
     }
 
-    fn tell(&mut self, value_spot: usize) -> Result<(), Error> {
-        todo!()
+    // *(Identifier StringLiteral) BrackClose
+    fn steps(&mut self) -> Result<Vec<Step>, Error> {
+        let mut steps = Vec::new();
+        loop {
+            self.next_token("'}' (Test ending)")?;
+            if self.curr_token.token_type == BrackClose {
+                break;
+            }
+
+            match self.step() {
+                Ok(step) => steps.push(step),
+                Err(error) => self.recover(error),
+            }
+        }
+
+        Ok(steps)
+    }
+
+    // answer, choose or shown StringLiteral
+    // assert StringLiteral EqualOp StringLiteral
+    fn step(&mut self) -> Result<Step, Error> {
+        let keyword = match &self.curr_token.token_type {
+            Identifier(keyword) => keyword.clone(),
+            _ => String::new(),
+        };
+
+        match keyword.as_str() {
+            "answer" => Ok(Step::Answer(self.expect_string("answer (StringLiteral) after `answer`")?)),
+            "choose" => Ok(Step::Choose(self.expect_string("option (StringLiteral) after `choose`")?)),
+            "shown" => Ok(Step::Shown(self.expect_string("message (StringLiteral) after `shown`")?)),
+            "assert" => {
+                let (left, right) = self.comparison()?;
+                Ok(Step::Assert(left, right))
+            },
+            _ => Err(Error::parse_error(
+                Expected("test step (`answer`, `choose`, `shown` or `assert`)".to_string()),
+                self
+            )),
+        }
     }
 
+    // BranchOp StringLiteral branches
     fn branch(&mut self) -> Result<(), Error> {
-        todo!()
+        let span = self.curr_token.span.clone();
+        let question = self.expect_string("question (StringLiteral) after '#'")?;
+
+        self.expect_if(|x| *x == BrackOpen, "'{' (Branch starting)")?;
+        self.branches(span.to(&question.span), question)
     }
 
-    fn branches() {
-        todo!()
+    // BrackOpen +option BrackClose
+    fn branches(&mut self, span: Span, question: Template) -> Result<(), Error> {
+        // Layout of the generated byte code -
+        // BRANCH, JMP(end), +(option body, JMP(end))
+        // Options compiling to a single instruction are kept as the handler itself
+        let start = self.byte_code.len();
+        self.emit(OpCode::NOP, span.clone());
+        self.emit(OpCode::NOP, span.clone());
+
+        let mut branches: Vec<Branch> = Vec::new();
+        let mut exits: Vec<usize> = Vec::new();
+
+        // Looping through all choices of the branch
+        loop {
+            match self.tokens.peek() {
+                Some(token) if token.token_type == BrackClose => {
+                    self.advance();
+                    break;
+                },
+                Some(_) => (),
+                None => return Err(Error::parse_error(
+                    Missing("'}' (Branch ending)".to_string()),
+                    self
+                )),
+            }
+
+            match self.option(branches.len(), &mut exits) {
+                Ok(branch) => branches.push(branch),
+                Err(error) => self.recover(error),
+            }
+        }
+
+        if branches.is_empty() {
+            return Err(Error::parse_error(
+                Expected("at least one Branch option".to_string()),
+                self
+            ))
+        }
+
+        if branches.len() == 1 {
+            self.warnings.push(Error::warning(SingleOption, span).with_help(
+                "the question could be a message ('-') instead"
+            ));
+        }
+
+        for (i, branch) in branches.iter().enumerate() {
+            let option = branch.option.to_string();
+            if branches[..i].iter().any(|earlier| earlier.option.to_string() == option) {
+                self.warnings.push(Error::warning(
+                    DuplicateOption(option), branch.option.span.clone()
+                ));
+            }
+        }
+
+        if exits.is_empty() {
+            self.unemit();
+        }
+        else {
+            let end = self.byte_code.len();
+            self.byte_code[start + 1] = OpCode::JMP(end);
+            for exit in exits {
+                self.byte_code[exit] = OpCode::JMP(end);
+            }
+        }
+        self.byte_code[start] = OpCode::BRANCH(question, branches);
+
+        Ok(())
     }
 
-    fn block() {
-        todo!()
+    // StringLiteral LambdaOp statement
+    // `exits` collects the jumps to be patched to the end of the branch
+    // The statement of the option is named `<branch>.<index>`
+    fn option(&mut self, index: usize, exits: &mut Vec<usize>) -> Result<Branch, Error> {
+        let option = self.expect_string("Branch option (StringLiteral)")?;
+        self.expect_if(|x| *x == LambdaOp, "'=>' (Branch option declaration)")?;
+        self.expect_if(|x| *x != BrackClose, "statement for the Branch option")?;
+
+        let entry = self.byte_code.len();
+        self.nested(self.node.clone(), index, |parser| parser.scoped(Self::statement))?;
+
+        let handler = match self.byte_code.len() - entry {
+            0 => OpCode::NOP,
+            1 => self.unemit(),
+            _ => {
+                exits.push(self.byte_code.len());
+                self.emit(OpCode::NOP, option.span.clone());
+                OpCode::JMP(entry)
+            },
+        };
+
+        Ok(Branch::new(option, handler))
     }
 
-    fn arguments() {
-        todo!()
+    // ?arguments BrackOpen *statement BrackClose
+    // Blocks being assigned are only defined, others run in place
+    fn block(&mut self, is_definition: bool) -> Result<(), Error> {
+        let parameters = if self.curr_token.token_type == ParOpen {
+            let parameters = self.arguments()?;
+            self.expect_if(|x| *x == BrackOpen, "'{' (Block starting)")?;
+            parameters
+        } else { vec![] };
+
+        if !is_definition {
+            if let Some((_, span)) = parameters.first() {
+                return Err(Error::at(
+                    Expected("name for the parameterised block (':=')".to_string()),
+                    span.clone()
+                ))
+            }
+
+            return self.nested(self.node.clone(), 0, |parser| parser.scoped(Self::statements));
+        }
+
+        // Layout of the generated byte code -
+        // JMP(end), *statement, RET
+        let start = self.byte_code.len();
+        self.emit(OpCode::NOP, self.curr_token.span.clone());
+
+        let block = self.blocks.len();
+        self.blocks.push(Block {
+            name: String::new(),
+            span: Span::default(),
+            parameters: parameters.iter().map(|(parameter, _)| parameter.clone()).collect(),
+            address: start + 1,
+            end: start + 1,
+            used: false,
+            variables: vec![],
+        });
+
+        // The block runs in it's own frame, with the arguments as it's first spots
+        self.defining.push(block);
+        let value_count = self.value_count;
+        let value_identifiers = std::mem::take(&mut self.value_identifiers);
+        for (spot, (parameter, span)) in parameters.into_iter().enumerate() {
+            self.values.push(Value {
+                name: parameter.clone(),
+                span,
+                spot,
+                used: false,
+                is_parameter: true,
+            });
+            self.value_identifiers.insert(parameter.clone(), self.values.len() - 1);
+            self.variable(parameter, spot);
+        }
+        self.value_count = self.value_identifiers.len();
+
+        let result = self.nested(self.node.clone(), 0, |parser| parser.scoped(Self::statements));
+        self.defining.pop();
+
+        self.value_count = value_count;
+        self.value_identifiers = value_identifiers;
+        result?;
+
+        self.emit(OpCode::RET, self.curr_token.span.clone());
+        let end = self.byte_code.len();
+        self.byte_code[start] = OpCode::JMP(end);
+        self.blocks[block].end = end;
+
+        Ok(())
     }
 
-    // StringLiteral
-    // Identifer
-    fn handle_token(&mut self) -> Result<OpCode, Error> {
-        match self.curr_token.token_type {
-            StringLiteral(_) => self.parse_strings(),
-            Identifier(_) => self.parse_identifier(),
-            _ => {
-                return Err(Error::parse_error(
-                    Expected("Message".to_string()),
+    // *statement BrackClose
+    fn statements(&mut self) -> Result<(), Error> {
+        loop {
+            self.next_token("'}' (Block ending)")?;
+            if self.curr_token.token_type == BrackClose {
+                break;
+            }
+
+            if let Err(error) = self.statement() {
+                self.recover(error);
+            }
+        }
+
+        Ok(())
+    }
+
+    // ParOpen +Identifier ParClose
+    fn arguments(&mut self) -> Result<Vec<(String, Span)>, Error> {
+        let mut arguments = Vec::new();
+        loop {
+            self.expect_if(|x| matches!(x, Identifier(_) | ParClose), "identifier (Argument)")?;
+            match self.curr_token.token_type.clone() {
+                Identifier(identifier) => arguments.push(
+                    (identifier, self.curr_token.span.clone())
+                ),
+                ParClose if !arguments.is_empty() => break,
+                _ => return Err(Error::parse_error(
+                    Expected("identifier (Argument)".to_string()),
                     self
+                )),
+            }
+        }
+
+        Ok(arguments)
+    }
+
+    // Identifier ?arguments
+    // An identifier being assigned is an alias, otherwise it's a block call
+    fn identifier(&mut self, identifier: String, is_assignment: bool) -> Result<ExprType, Error> {
+        let span = self.curr_token.span.clone();
+
+        if is_assignment {
+            if let Some(&original) = self.value_identifiers.get(&identifier) {
+                return Ok(ExprType::Reference(original, ImmediateType::Value));
+            }
+            if let Some(&original) = self.block_identifiers.get(&identifier) {
+                return Ok(ExprType::Reference(original, ImmediateType::Block));
+            }
+            let declared = self.value_identifiers.keys().chain(self.block_identifiers.keys());
+            return Err(self.undeclared(
+                UndeclaredIdentifier(identifier.clone()), &identifier, declared, span
+            ));
+        }
+
+        let block = match self.block_identifiers.get(&identifier) {
+            Some(&block) => block,
+            None if self.value_identifiers.contains_key(&identifier) => {
+                return Err(Error::at(
+                    Expected(format!("block to call, '{}' is a value", identifier)),
+                    span
                 ))
             },
+            None => return Err(self.undeclared(
+                UndeclaredIdentifier(identifier.clone()),
+                &identifier,
+                self.block_identifiers.keys(),
+                span
+            )),
+        };
+
+        let arguments = if self.tokens.next_if(|x| x.token_type == ParOpen).is_some() {
+            self.arguments()?
+        } else { vec![] };
+
+        let mut spots = Vec::new();
+        for (argument, argument_span) in arguments {
+            match self.value_identifiers.get(&argument) {
+                Some(&value) => {
+                    self.values[value].used = true;
+                    spots.push(self.values[value].spot);
+                },
+                None => return Err(self.undeclared(
+                    UndeclaredIdentifier(argument.clone()),
+                    &argument,
+                    self.value_identifiers.keys(),
+                    argument_span
+                )),
+            }
+        }
+
+        let arity = self.blocks[block].parameters.len();
+        if spots.len() != arity {
+            return Err(Error::at(
+                Expected(format!("{} argument(s) for block '{}'", arity, identifier)),
+                span
+            ))
+        }
+
+        // Blocks calling themselves still count as never called
+        if !self.defining.contains(&block) {
+            self.blocks[block].used = true;
         }
+
+        self.emit(OpCode::CALL(self.blocks[block].address, spots), span);
+        Ok(ExprType::Immediate(ImmediateType::Block))
     }
 
-    // StringLiteral BrackOpen
-    // StringLiteral
-    fn parse_strings(&mut self) -> Result<OpCode, Error> {
-        self.validate_string()?;
-        let message = match self.curr_token.token_type.clone() {
-            StringLiteral(m) => m,
-            _ => unreachable!(),
+    // Keeping the variable by name in the frame it's declared in, the block being
+    // defined or the top level
+    fn variable(&mut self, name: String, spot: usize) {
+        let variable = Variable { name, spot, address: self.byte_code.len() };
+        match self.defining.last() {
+            Some(&block) => self.blocks[block].variables.push(variable),
+            None => self.variables.push(variable),
+        }
+    }
+
+    // Identifiers declared inside `f` are dropped after it
+    fn scoped(
+        &mut self, f: impl FnOnce(&mut Self) -> Result<(), Error>
+    ) -> Result<(), Error> {
+        let value_identifiers = self.value_identifiers.clone();
+        let block_identifiers = self.block_identifiers.clone();
+
+        let result = f(self);
+
+        self.value_identifiers = value_identifiers;
+        self.block_identifiers = block_identifiers;
+        result
+    }
+
+    // Warning for a declaration hiding an earlier one
+    fn shadowing(&mut self, identifier: &str, span: &Span) {
+        let earlier = match self.value_identifiers.get(identifier) {
+            Some(&value) => &self.values[value].span,
+            None => match self.block_identifiers.get(identifier) {
+                Some(&block) => &self.blocks[block].span,
+                None => return,
+            },
         };
 
-        if let Some(_) =
-            self.tokens.next_if(|x| x.token_type == BrackOpen) {
-                self.parse_branch(message);
+        let warning = Error::warning(Shadowing(identifier.to_string()), span.clone())
+            .with_help(format!("declared earlier at line {}", earlier.start.line));
+        self.warnings.push(warning);
+    }
+
+    // Tests run along the whole story, the values they refer to could be declared anywhere
+    // in it, even after them
+    fn tested(&mut self) {
+        let declared: Vec<&String> = self.variables.iter()
+            .chain(self.blocks.iter().flat_map(|block| &block.variables))
+            .map(|variable| &variable.name)
+            .collect();
+
+        for (identifier, span) in &self.tested {
+            if !declared.contains(&identifier) {
+                let error = self.undeclared(
+                    InvalidIdentifier(identifier.clone()),
+                    identifier,
+                    declared.iter().copied(),
+                    span.clone(),
+                );
+                self.errors.push(error);
+            }
         }
-        else {
+    }
+
+    // Warnings for variables asked but never interpolated,
+    // and for blocks never called
+    fn unused(&mut self) {
+        for value in &self.values {
+            if !value.used && !value.is_parameter {
+                self.warnings.push(Error::warning(
+                    UnusedVariable(value.name.clone()), value.span.clone()
+                ).with_help(format!("interpolate it as `${}$`, or drop the assignment", value.name)));
+            }
         }
-        unreachable!()
 
+        for block in &self.blocks {
+            if !block.used && !block.name.is_empty() {
+                self.warnings.push(Error::warning(
+                    UnusedBlock(block.name.clone()), block.span.clone()
+                ));
+            }
+        }
     }
 
-    // Identifer StringLiteral
-    fn parse_identifier(&mut self) -> Result<OpCode, Error> {
-        let save_id = match self.curr_token.token_type {
-            Identifier(m) => m,
-            _ => unreachable!(),
-        };
+    // Error for an undeclared identifier, suggesting a similar declared one
+    fn undeclared<'a>(
+        &self,
+        error_type: ErrorType,
+        identifier: &str,
+        declared: impl Iterator<Item = &'a String>,
+        span: Span,
+    ) -> Error {
+        let error = Error::at(error_type, span);
+        let similar = declared
+            .map(|candidate| (distance(identifier, candidate), candidate))
+            .filter(|&(distance, _)| distance <= 2)
+            .min();
+
+        match similar {
+            Some((_, candidate)) => error.with_help(format!("did you mean `{}`?", candidate)),
+            None => error,
+        }
+    }
 
-        let question_token = if let Some(token) = self.tokens.next() {
-            self.curr_token = token.clone();
-            token
-        } else {
-            return Err(Error::parse_error(
-                Missing("question (StringLiteral)".to_string()),
-                self
-            ))
-        };
-        let question = if let StringLiteral(question) = question_token.token_type {
-            self.validate_string()?;
-            question
+    // Keeping the error, then skipping to the next line's statement or '}'
+    // Braces being skipped are matched, for them to not end the wrong block
+    fn recover(&mut self, error: Error) {
+        let line = error.span.end.line.max(self.curr_token.span.end.line);
+
+        // Nested blocks missing their ending complain at the same place
+        if self.errors.last().map(|last| &last.span) != Some(&error.span) {
+            self.errors.push(error);
+        }
+
+        let mut depth = 0;
+        while let Some(token) = self.tokens.peek() {
+            match token.token_type {
+                BrackClose if depth == 0 => break,
+                BrackClose => depth -= 1,
+                BrackOpen => depth += 1,
+                _ if depth == 0 && token.span.start.line > line => break,
+                _ => (),
+            }
+            self.advance();
+        }
+    }
+
+    // Moves to the next token, returns false at the end of tokens
+    fn advance(&mut self) -> bool {
+        if let Some(token) = self.tokens.next() {
+            self.curr_token = token;
+            true
+        } else { false }
+    }
+
+    // Moves to the next token, `expected` being what's missing otherwise
+    fn next_token(&mut self, expected: &str) -> Result<(), Error> {
+        if self.advance() {
+            Ok(())
         } else {
-            return Err(Error::parse_error(
-                Expected("question (StringLiteral)".to_string()),
-                self
-            ))
-        };
+            Err(Error::parse_error(Missing(expected.to_string()), self))
+        }
+    }
 
-        unreachable!()
+    // Moves to the next token only if it matches,
+    // otherwise complains about it without consuming it
+    fn expect_if(
+        &mut self, predicate: impl Fn(&TokenType) -> bool, expected: &str
+    ) -> Result<(), Error> {
+        match self.tokens.peek() {
+            Some(token) if predicate(&token.token_type) => {
+                self.advance();
+                Ok(())
+            },
+            Some(token) => Err(Error::at(Expected(expected.to_string()), token.span.clone())),
+            None => Err(Error::parse_error(Missing(expected.to_string()), self)),
+        }
     }
 
-    // BrackOpen +(StringLiteral LambdaOperator BrackOpen *. BrackClose) BrackClose
-    fn parse_branch(&mut self, question: String) -> Result<OpCode, Error> {
-        let mut branches: Vec<Branch> = Vec::new();
+    // Next token as a StringLiteral compiled into a Template
+    fn expect_string(&mut self, expected: &str) -> Result<Template, Error> {
+        self.expect_if(|x| matches!(x, StringLiteral(_)), expected)?;
+        match self.curr_token.token_type.clone() {
+            StringLiteral(literal) => self.template(&literal),
+            _ => unreachable!(),
+        }
+    }
 
-        // Looping through all choices of the branch
-        while let None = self.tokens.next_if(|x| x.token_type == BrackClose) {
-            let branch_name_token = if let Some(token) = self.tokens.next() {
-                self.curr_token = token.clone();
-                token
-            } else {
-                return Err(Error::parse_error(
-                    Missing("'}' (Branch ending)".to_string()),
-                    self
-                ))
-            };
+    // Splitting StringLiteral into literal pieces and identifier references
+    // Identifer reference be -> `$<identifier>$`, and `$$` be a literal '$'
+    // Also checks that every reference is a declared value
+    fn template(&mut self, info: &str) -> Result<Template, Error> {
+        // Position of the next letter, for errors to point inside the literal
+        let mut position = self.curr_token.span.start.clone();
+        position.advance('"');
+
+        let mut template = Template {
+            pieces: vec![],
+            span: self.curr_token.span.clone(),
+        };
+        let mut literal = String::new();
 
-            let branch_name = if let StringLiteral(branch_name) = branch_name_token.token_type {
-                self.validate_string()?;
-                branch_name
-            } else {
-                return Err(Error::parse_error(
-                    Expected("Branch Node".to_string()),
-                    self
-                ))
-            };
+        let mut letters = info.chars().peekable();
+        while let Some(letter) = letters.next() {
+            let start = position.clone();
+            position.advance(letter);
 
-            let node_delaration = if let Some(token) = self.tokens.next() {
-                self.curr_token = token.clone();
-                token
-            } else {
-                return Err(Error::parse_error(
-                    Missing("'=>' (Branch Node declaration)".to_string()),
-                    self
-                ))
-            };
-                return Err(Error::parse_error(
-                    Expected("'=>' (Branch Node declaration)".to_string()),
-                    self
-                ))
+            if letter != '$' {
+                literal.write_char(letter).unwrap();
+                continue;
+            }
 
-            let node_starting = if let Some(token) = self.tokens.next() {
-                self.curr_token = token.clone();
-                token
-            } else {
-                return Err(Error::parse_error(
-                    Missing("'{' (Branch Node starting)".to_string()),
-                    self
-                ))
-            };
-            if node_starting.token_type != BrackOpen {
-                return Err(Error::parse_error(
-                    Expected("'{' (Branch Node starting)".to_string()),
-                    self
-                ))
-            };
+            if letters.next_if_eq(&'$').is_some() {
+                position.advance('$');
+                literal.write_char('$').unwrap();
+                continue;
+            }
 
-            let mut branch_node_block: Vec<OpCode> = Vec::new();
-            let tmp = self.value_identifiers.clone();
-
-            // Looping through all tokens inside the branch body
-            // to recursively parse them all
-            while let None = self.tokens.next_if(|x| x.token_type == BrackClose) {
-                if let Some(token) = self.tokens.next() {
-                    self.curr_token = token.clone();
-                    token
-                } else {
-                    return Err(Error::parse_error(
-                        Missing("'}' (Branch Node ending)".to_string()),
-                        self
-                    ))
-                };
+            let mut identifier = String::new();
+            while let Some(letter) = letters.next_if(char::is_ascii_alphanumeric) {
+                position.advance(letter);
+                identifier.write_char(letter).unwrap();
+            }
+
+            if identifier.is_empty() {
+                return Err(Error::at(
+                    Expected("identifier after '$'".to_string()),
+                    Span::new(start, position)
+                ).with_help("use `$$` for a literal '$'"))
+            }
+
+            if letters.next_if_eq(&'$').is_none() {
+                return Err(Error::at(
+                    Missing(format!("'$' after the identifier reference '${}'", identifier)),
+                    Span::new(start, position)
+                ).with_help(format!("identifier references are written as `${}$`", identifier)))
+            }
+            position.advance('$');
+
+            let spot = match self.value_identifiers.get(&identifier) {
+                _ if self.in_test => {
+                    self.tested.push((identifier.clone(), Span::new(start, position.clone())));
+                    0
+                },
+                Some(&value) => {
+                    self.values[value].used = true;
+                    self.values[value].spot
+                },
+                None => return Err(self.undeclared(
+                    InvalidIdentifier(identifier.clone()),
+                    &identifier,
+                    self.value_identifiers.keys(),
+                    Span::new(start, position)
+                )),
+            };
 
-                branch_node_block.push(self.handle_token()?);
+            if !literal.is_empty() {
+                template.pieces.push(Piece::Literal(std::mem::take(&mut literal)));
             }
-            self.value_identifiers = tmp;
+            template.pieces.push(Piece::Reference(identifier, spot));
+        }
 
-            branches.push(Branch::new(branch_name, branch_node_block))
+        if !literal.is_empty() {
+            template.pieces.push(Piece::Literal(literal));
         }
 
-        Ok(OpCode::new_branch(question, branches))
+        Ok(template)
     }
+}
 
-    // Checking if StringLiteral have valid identifier references
-    // Identifer reference be -> `$<*digit>`
-    fn validate_string(&self) -> Result<(), Error> {
-        let info = if let StringLiteral(m) =
-            self.curr_token.token_type.clone() { m } else { unreachable!() };
+// Levenshtein distance between two identifiers
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
 
-        let mut i = 0;
-        while i < info.len() {
-            let mut letter = info.chars().nth(i).unwrap();
-            if letter == '$' {
-                i += 1;
-                if i >= info.len() { break; }
-                letter = info.chars().nth(i).unwrap();
+    for (i, letter) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, other) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(letter != *other);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
 
-                let mut number = String::new();
-                while DIGITS.contains(&letter) {
-                    number.write_char(letter).unwrap();
-                    i += 1;
-                    if i >= info.len() { break; }
-                    letter = info.chars().nth(i).unwrap();
-                }
+    previous[b.len()]
+}
 
-                if !number.is_empty() {
-                    let number: usize = number.parse().unwrap();
-                    if !self.value_identifiers.contains(&number) {
-                        //"Identifer '{}' used in StringLiteral without delaration",
-                        return Err(Error::parse_error(
-                            InvalidIdentifier(number),
-                            self
-                        ))
-                    }
-                }
+// Lines silenced by `// allow(<warning>, ...)` comments, with the warnings silenced
+// A comment silences it's own line and the next one
+fn allowed(comments: &[(Span, String)]) -> HashMap<usize, Vec<String>> {
+    let mut allowed: HashMap<usize, Vec<String>> = HashMap::new();
+    for (span, comment) in comments {
+        let names = comment.trim()
+            .strip_prefix("allow(")
+            .and_then(|names| names.strip_suffix(')'));
+
+        if let Some(names) = names {
+            let names: Vec<String> = names.split(',').map(|name| name.trim().to_string()).collect();
+            for line in [span.start.line, span.start.line + 1] {
+                allowed.entry(line).or_default().extend(names.iter().cloned());
             }
+        }
+    }
+    allowed
+}
 
-            i += 1;
+pub fn compile(source: &str) -> Result<Program, Vec<Error>> {
+    compile_with(source, &CompileOptions::default())
+}
+
+// Every error of the source, sorted by position, is reported at once
+// Warnings are only reported along with a successful compilation
+pub fn compile_with(source: &str, options: &CompileOptions) -> Result<Program, Vec<Error>> {
+    let (tokens, comments, mut errors) = Lexer::lex(source);
+    let (mut program, parse_errors) = Parser::parse(tokens, options.debug);
+    errors.extend(parse_errors);
+
+    if errors.is_empty() && options.analyse {
+        for diagnostic in analyse(&program) {
+            match diagnostic.severity {
+                Severity::Error => errors.push(diagnostic),
+                Severity::Warning => program.warnings.push(diagnostic),
+            }
         }
-        Ok(())
     }
+
+    if errors.is_empty() {
+        program.undo = !comments.iter().any(|(_, comment)| comment.trim() == "no-undo");
+
+        let allowed = allowed(&comments);
+        let warnings = &mut program.warnings;
+        warnings.retain(|warning| {
+            let names = allowed.get(&warning.span.start.line);
+            let name = warning.error_type.lint_name().unwrap_or_default();
+            !names.is_some_and(|names| names.iter().any(|allowed| allowed == name))
+        });
+
+        warnings.sort_by_key(|warning| warning.span.start.offset);
+        warnings.truncate(options.error_limit);
+        return Ok(program);
+    }
+
+    errors.sort_by_key(|error| error.span.start.offset);
+    errors.truncate(options.error_limit);
+    Err(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_types(source: &str) -> Vec<ErrorType> {
+        match compile(source) {
+            Ok(_) => vec![],
+            Err(errors) => errors.into_iter().map(|error| error.error_type).collect(),
+        }
+    }
+
+    // Blocks failing on their parameters used to be left declared, without being pushed
+    #[test]
+    fn unfinished_main_block() {
+        assert_eq!(error_types("main := ("), vec![Missing("identifier (Argument)".to_string())]);
+    }
+
+    #[test]
+    fn call_of_block_failing_on_parameters() {
+        let errors = error_types("f := ( ) { }\nf\n");
+        assert_eq!(errors[0], Expected("identifier (Argument)".to_string()));
+        assert_eq!(errors[1], UndeclaredIdentifier("f".to_string()));
+    }
+
+    #[test]
+    fn earlier_block_kept_when_redefinition_fails() {
+        let errors = error_types("f := { - \"a\" }\nf\nf := ( ) { }\nf\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0], Expected("identifier (Argument)".to_string()));
+    }
+
+    fn token_types(source: &str) -> Vec<TokenType> {
+        Lexer::lex(source).0.into_iter().map(|token| token.token_type).collect()
+    }
+
+    fn position(line: usize, column: usize, offset: usize) -> Position {
+        Position { line, column, offset }
+    }
+
+    // Columns count chars, offsets count bytes
+    #[test]
+    fn non_ascii_literals() {
+        let source = "- \"Héllo 😀\" x\n- \"é\"";
+        let (tokens, _, errors) = Lexer::lex(source);
+        assert!(errors.is_empty());
+
+        assert_eq!(tokens[1].token_type, StringLiteral("Héllo 😀".to_string()));
+        assert_eq!(tokens[1].span, Span::new(position(1, 3, 2), position(1, 12, 15)));
+        assert_eq!(tokens[1].span.slice(source), "\"Héllo 😀\"");
+        assert_eq!(tokens[2].span, Span::new(position(1, 13, 16), position(1, 14, 17)));
+        assert_eq!(tokens[4].span, Span::new(position(2, 3, 20), position(2, 6, 24)));
+        assert_eq!(tokens[4].span.slice(source), "\"é\"");
+    }
+
+    #[test]
+    fn template_errors_point_inside_non_ascii_literals() {
+        let errors = compile("- \"é $nope$\"").err().unwrap();
+        assert_eq!(errors[0].span, Span::new(position(1, 6, 6), position(1, 12, 12)));
+    }
+
+    #[test]
+    fn operators_at_end_of_input() {
+        let (tokens, _, errors) = Lexer::lex("a :");
+        assert_eq!(tokens.last().unwrap().token_type, AssignmentOp);
+        assert_eq!(
+            errors[0].error_type, Missing("'=' after ':', for AssignmentOp (':=')".to_string())
+        );
+        assert_eq!(errors[0].span, Span::new(position(1, 3, 2), position(1, 4, 3)));
+
+        let (tokens, _, errors) = Lexer::lex("\"a\" =");
+        assert_eq!(tokens.last().unwrap().token_type, LambdaOp);
+        assert_eq!(errors[0].error_type, Missing("'>' after '=', for '=>' operator".to_string()));
+
+        assert_eq!(token_types("a := b == c"), vec![
+            Identifier("a".to_string()), AssignmentOp, Identifier("b".to_string()),
+            EqualOp, Identifier("c".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn unterminated_string() {
+        let (tokens, _, errors) = Lexer::lex("- \"abc\n");
+        assert_eq!(tokens[1].token_type, StringLiteral("abc\n".to_string()));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].error_type, Missing("This '\"' (String literal) have no ending".to_string())
+        );
+        assert_eq!(errors[0].span, Span::new(position(1, 3, 2), position(2, 1, 7)));
+    }
+
+    #[test]
+    fn templates() {
+        let program = compile("name := ? \"Name?\"\n- \"$$5 for $name$$$\"").unwrap();
+        let message = program.byte_code.iter().find_map(|op_code| match op_code {
+            OpCode::TELL(message) => Some(message),
+            _ => None,
+        }).unwrap();
+
+        match message.pieces.as_slice() {
+            [Piece::Literal(before), Piece::Reference(name, 0), Piece::Literal(after)] => {
+                assert_eq!(before, "$5 for ");
+                assert_eq!(name, "name");
+                assert_eq!(after, "$");
+            },
+            pieces => panic!("unexpected pieces {:?}", pieces),
+        }
+    }
+
+    #[test]
+    fn template_errors() {
+        assert_eq!(error_types("- \"$ x\""), vec![Expected("identifier after '$'".to_string())]);
+        assert_eq!(
+            error_types("name := ? \"Name?\"\n- \"$name\""),
+            vec![Missing("'$' after the identifier reference '$name'".to_string())]
+        );
+    }
+
+    #[test]
+    fn tests_refer_to_values_anywhere() {
+        let story = "\
+test \"t\" {
+    assert \"$name$ $mood$\" == \"Bob ok\"
+}
+name := ? \"Name?\"
+f := {
+    mood := ? \"Mood?\"
 }
+f
+";
+        assert!(compile(story).is_ok());
+
+        let errors = compile(&story.replace("$name$ ", "$nmae$ ")).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].error_type, InvalidIdentifier("nmae".to_string()));
+        assert_eq!(errors[0].help.as_deref(), Some("did you mean `name`?"));
+        assert_eq!(errors[0].span, Span::new(position(2, 13, 23), position(2, 19, 29)));
+    }
+
+    fn warnings(source: &str) -> Vec<(usize, ErrorType)> {
+        compile(source).unwrap().warnings.into_iter()
+            .map(|warning| (warning.span.start.line, warning.error_type))
+            .collect()
+    }
 
-pub fn compile(source: String) -> Result<Vec<OpCode>, Error> {
-    let tokens = Lexer::lex(source)?;
-    let messages = Parser::parse(tokens)?;
+    const WARNED: &str = "\
+name := ? \"Name?\"
+f := { - \"f\" }
+name := ? \"Again?\"
+# \"Where?\" {
+    \"Cave\" => - \"$name$\"
+    \"Cave\" => - \"Dark\"
+}
+# \"Sure?\" {
+    \"Yes\" => - \"Ok\"
+}
+";
+
+    #[test]
+    fn warnings_are_sorted_by_position() {
+        assert_eq!(warnings(WARNED), vec![
+            (1, UnusedVariable("name".to_string())),
+            (2, UnusedBlock("f".to_string())),
+            (3, Shadowing("name".to_string())),
+            (6, DuplicateOption("Cave".to_string())),
+            (8, SingleOption),
+        ]);
+    }
 
-    Ok(messages)
+    #[test]
+    fn warnings_are_capped() {
+        let options = CompileOptions { error_limit: 2, ..CompileOptions::default() };
+        assert_eq!(compile_with(WARNED, &options).unwrap().warnings.len(), 2);
+    }
+
+    #[test]
+    fn warnings_are_left_out_of_errors() {
+        assert_eq!(error_types(&format!("{}\n- \"$nope$\"\n", WARNED)).len(), 1);
+    }
+
+    // A comment silences the named warnings on it's own line and the next one only
+    #[test]
+    fn allowed_warnings() {
+        let story = WARNED
+            .replace("f := {", "// allow(unused-variable, unused-block)\nf := {")
+            .replace("# \"Sure?\"", "// allow(single-option)\n# \"Sure?\"");
+        assert_eq!(warnings(&story), vec![
+            (1, UnusedVariable("name".to_string())),
+            (4, Shadowing("name".to_string())),
+            (7, DuplicateOption("Cave".to_string())),
+        ]);
+
+        let again = "name := ? \"Again?\"";
+        let story = WARNED.replace(again, &format!("// allow(shadowing)\n{}", again));
+        assert!(!warnings(&story).contains(&(4, Shadowing("name".to_string()))));
+
+        let story = WARNED.replace("f := {", "// allow(shadowing)\nf := {");
+        assert!(warnings(&story).contains(&(3, UnusedBlock("f".to_string()))));
+    }
 }
@@ -1,10 +1,34 @@
-use pancurses::*;
-use std::{thread, time, fmt::Write};
-
 mod models;     // Constants and data structures
 mod runtime;    // The ast interpreter
+mod curses;     // The pancurses frontend
+mod plain;      // The line oriented frontend
 mod lang;       // The rpg compiler
-pub use lang::compile;
+mod diagnostics; // Rendering compiler errors
+mod graph;      // Exporting the story graph
+mod analysis;   // Reachability of the compiled story
+mod bytecode;   // Serialising the compiled story
+mod save;       // Saving and loading the game state
+mod slots;      // Save slots and the pause menu
+mod replay;     // Recording and replaying the inputs
+mod transcript; // Golden transcripts of stories
+mod testing;    // Running the test blocks of stories
+mod explore;    // Playing every path of the story
+mod coverage;   // Reporting the parts of the story never reached
+mod format;     // Formatting rpg sources
+pub use lang::{compile, compile_with};
+pub use diagnostics::report;
+pub use graph::graph;
+pub use analysis::analyse;
+pub use format::format;
 pub use models::*;
-pub use runtime::start_game;
+pub use runtime::{start_game, interact};
+pub use bytecode::story_hash;
+pub use curses::Curses;
+pub use plain::Plain;
+pub use slots::{Slots, MenuAction, pause_menu, warn};
+pub use replay::{Recorder, Replay};
+pub use transcript::{transcript, diff};
+pub use testing::run_test;
+pub use explore::explore;
+pub use coverage::{coverage_summary, annotate};
 
@@ -1,39 +1,732 @@
-use pancurses::*;
 use rpg_rs::*;
-use std::{env, fs};
-
-fn main() -> Result<(), i32> {
-    // Command line args
-    let args: Vec<String> = env::args().collect();
-    if args.len() <= 1 {
-        println!("Usage: <file_name>");
-        return Err(0);
-    }
-
-    // First command line argument be rpg source file name
-    let source = if let Ok(m) = fs::read_to_string(args[1].clone()) {
-        m
-    } else {
-        eprintln!("Error: Error while reading the file");
-        return Err(-1);
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::{env, fs, process::ExitCode};
+
+const USAGE: &str = "\
+Usage: rpg-rs [command] [options] <file_name>
+
+Commands:
+    run      Play the story, the default command (compiled stories included)
+    check    Compile the story and report the diagnostics only
+    build    Compile the story into byte code, `<file_name>.rpgc` by default
+    graph    Print the story graph
+    fmt      Format the story in place
+    test     Run the `test` blocks of the story, and play it with
+             `<file_name>.input` typed in, comparing the transcript against
+             `<file_name>.expected` if there's one
+    explore  Play every option of every branch, reporting the paths that fail
+             or never end
+
+Options:
+    --message-format=<human|json>    How the diagnostics are reported
+    --error-limit=<n>                Errors reported at most, 20 by default
+    --format=<dot|mermaid>           Format of the story graph (graph)
+    --output=<file_name>             Where the byte code is written (build)
+    --debug                          Keep the asserts in the byte code (build)
+    --check                          Only report unformatted stories (fmt)
+    --input=<file_name>              Lines typed into the story (test)
+    --expected=<file_name>           The transcript expected (test)
+    --bless                          Write the transcript as the expected one (test)
+    --answers=<file_name>            Answers to the questions, a line each as
+                                     `<question> => <answer>` (explore)
+    --coverage                       Report the tells, asks and options never
+                                     reached, annotating the source into
+                                     `<file_name>.cov` (test, explore)
+    --path-limit=<n>                 Paths explored at most, 100000 by default
+                                     (explore)
+    --depth-limit=<n>                Questions and choices along a path at most,
+                                     1000 by default (explore)
+    --ui=<curses|plain>              How the story is played, plain when stdout
+                                     isn't a terminal by default (run)
+    --load=<file_name>               Resume the story from a save (run)
+    --save=<file_name>               Keep saving the progress into it (run)
+    --saves=<directory>              Where the save slots and the autosave are
+                                     kept, `<file_name>.saves` by default (run)
+    --record=<file_name>             Write every input into a session log (run)
+    --replay=<file_name>             Play the inputs of a session log back (run)
+    --headless                       Replay without printing the story (run)
+    --undo-depth=<n>                 Choices that could be undone, 20 by default,
+                                     0 disabling undo (run)
+    -h, --help                       Print this help
+    -V, --version                    Print the version
+
+Exit codes:
+    0        Success
+    1        Failure reading or writing a file, an unformatted story, or a
+             failing test or exploration
+    2        Invalid command line usage
+    3        Compile errors in the story, reported along with the warnings";
+
+// Exit code of stories failing to compile, whatever the errors are
+const COMPILE_ERROR: i32 = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Run,
+    Check,
+    Build,
+    Graph,
+    Fmt,
+    Test,
+    Explore,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Ui {
+    Curses,
+    Plain,
+}
+
+struct Args {
+    command: Command,
+    file_name: String,
+    format: MessageFormat,
+    options: CompileOptions,
+    graph_format: Option<GraphFormat>,
+    output: Option<String>,
+    check: bool,
+    input: Option<String>,
+    expected: Option<String>,
+    bless: bool,
+    answers: Option<String>,
+    coverage: bool,
+    path_limit: Option<usize>,
+    depth_limit: Option<usize>,
+    ui: Option<Ui>,
+    load: Option<String>,
+    save: Option<String>,
+    saves: Option<String>,
+    undo_depth: Option<usize>,
+    record: Option<String>,
+    replay: Option<String>,
+    headless: bool,
+}
+
+fn main() -> ExitCode {
+    // Rust reports any `Err` returned from `main` as 1, the code is passed on instead
+    match cli() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => ExitCode::from(code as u8),
+    }
+}
+
+fn cli() -> Result<(), i32> {
+    let args = parse_args(env::args().skip(1))?;
+    match args.command {
+        Command::Run => run(&args),
+        Command::Check => check(&args),
+        Command::Build => build(&args),
+        Command::Graph => graph_command(&args),
+        Command::Fmt => fmt(&args),
+        Command::Test => test(&args),
+        Command::Explore => explore_command(&args),
+    }
+}
+
+// `Err(0)` being a successful early exit, like for `--help`
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, i32> {
+    let mut command = None;
+    let mut file_name = None;
+    let mut format = MessageFormat::Human;
+    let mut options = CompileOptions::default();
+    let mut graph_format = None;
+    let mut output = None;
+    let mut check = false;
+    let mut input = None;
+    let mut expected = None;
+    let mut bless = false;
+    let mut answers = None;
+    let mut coverage = false;
+    let mut path_limit = None;
+    let mut depth_limit = None;
+    let mut ui = None;
+    let mut load = None;
+    let mut save = None;
+    let mut saves = None;
+    let mut undo_depth = None;
+    let mut debug = false;
+    let mut record = None;
+    let mut replay = None;
+    let mut headless = false;
+
+    while let Some(arg) = args.next() {
+        // `--option value` being the same as `--option=value`
+        let (option, inline) = match arg.split_once('=') {
+            Some((option, value)) if arg.starts_with("--") => {
+                (option.to_string(), Some(value.to_string()))
+            },
+            _ => (arg.clone(), None),
+        };
+        let mut value = || inline.clone().or_else(|| args.next()).ok_or_else(|| {
+            usage_error(&format!("Missing the value of '{}'", option))
+        });
+
+        match option.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Err(0);
+            },
+            "-V" | "--version" => {
+                println!("rpg-rs {}", env!("CARGO_PKG_VERSION"));
+                return Err(0);
+            },
+            "--message-format" => format = match value()?.as_str() {
+                "human" => MessageFormat::Human,
+                "json" => MessageFormat::Json,
+                other => return Err(usage_error(&format!("Unknown message format '{}'", other))),
+            },
+            "--format" => graph_format = Some(match value()?.as_str() {
+                "dot" => GraphFormat::Dot,
+                "mermaid" => GraphFormat::Mermaid,
+                other => return Err(usage_error(&format!("Unknown graph format '{}'", other))),
+            }),
+            "--ui" => ui = Some(match value()?.as_str() {
+                "curses" => Ui::Curses,
+                "plain" => Ui::Plain,
+                other => return Err(usage_error(&format!("Unknown ui '{}'", other))),
+            }),
+            "--error-limit" => {
+                let limit = value()?;
+                match limit.parse() {
+                    Ok(limit) => options.error_limit = limit,
+                    Err(_) => return Err(usage_error(&format!("Invalid error limit '{}'", limit))),
+                }
+            },
+            "--path-limit" => {
+                let limit = value()?;
+                match limit.parse() {
+                    Ok(limit) => path_limit = Some(limit),
+                    Err(_) => return Err(usage_error(&format!("Invalid path limit '{}'", limit))),
+                }
+            },
+            "--depth-limit" => {
+                let limit = value()?;
+                match limit.parse() {
+                    Ok(limit) => depth_limit = Some(limit),
+                    Err(_) => return Err(usage_error(&format!("Invalid depth limit '{}'", limit))),
+                }
+            },
+            "--undo-depth" => {
+                let depth = value()?;
+                match depth.parse() {
+                    Ok(depth) => undo_depth = Some(depth),
+                    Err(_) => return Err(usage_error(&format!("Invalid undo depth '{}'", depth))),
+                }
+            },
+            "--output" => output = Some(value()?),
+            "--load" => load = Some(value()?),
+            "--save" => save = Some(value()?),
+            "--saves" => saves = Some(value()?),
+            "--record" => record = Some(value()?),
+            "--replay" => replay = Some(value()?),
+            "--headless" => headless = true,
+            "--debug" => debug = true,
+            "--check" => check = true,
+            "--input" => input = Some(value()?),
+            "--expected" => expected = Some(value()?),
+            "--bless" => bless = true,
+            "--answers" => answers = Some(value()?),
+            "--coverage" => coverage = true,
+            _ if arg.starts_with('-') => {
+                return Err(usage_error(&format!("Unknown option '{}'", arg)))
+            },
+
+            // The first positional argument could be the command
+            _ if command.is_none() && file_name.is_none() => {
+                command = Some(match arg.as_str() {
+                    "run" => Command::Run,
+                    "check" => Command::Check,
+                    "build" => Command::Build,
+                    "graph" => Command::Graph,
+                    "fmt" => Command::Fmt,
+                    "test" => Command::Test,
+                    "explore" => Command::Explore,
+                    _ => {
+                        file_name = Some(arg);
+                        Command::Run
+                    },
+                });
+            },
+            _ if file_name.is_none() => file_name = Some(arg),
+            _ => return Err(usage_error(&format!("Unexpected argument '{}'", arg))),
+        }
+    }
+
+    let command = command.unwrap_or(Command::Run);
+    let misplaced = [
+        ("--format", graph_format.is_some(), Command::Graph),
+        ("--output", output.is_some(), Command::Build),
+        ("--debug", debug, Command::Build),
+        ("--check", check, Command::Fmt),
+        ("--input", input.is_some(), Command::Test),
+        ("--expected", expected.is_some(), Command::Test),
+        ("--bless", bless, Command::Test),
+        ("--answers", answers.is_some(), Command::Explore),
+        ("--path-limit", path_limit.is_some(), Command::Explore),
+        ("--depth-limit", depth_limit.is_some(), Command::Explore),
+        ("--ui", ui.is_some(), Command::Run),
+        ("--load", load.is_some(), Command::Run),
+        ("--save", save.is_some(), Command::Run),
+        ("--saves", saves.is_some(), Command::Run),
+        ("--undo-depth", undo_depth.is_some(), Command::Run),
+        ("--record", record.is_some(), Command::Run),
+        ("--replay", replay.is_some(), Command::Run),
+        ("--headless", headless, Command::Run),
+    ];
+    for (option, is_given, only) in misplaced {
+        if is_given && command != only {
+            return Err(usage_error(&format!(
+                "Option '{}' is only for the `{}` command", option, name(only)
+            )));
+        }
+    }
+
+    if coverage && !matches!(command, Command::Test | Command::Explore) {
+        return Err(usage_error("Option '--coverage' is only for the `test` and `explore` commands"));
+    }
+    if record.is_some() && load.is_some() {
+        return Err(usage_error("Sessions are recorded from the start, '--record' can't go with '--load'"));
+    }
+    if headless && replay.is_none() {
+        return Err(usage_error("Option '--headless' is only for replays, along with '--replay'"));
+    }
+
+    let file_name = match file_name {
+        Some(file_name) => file_name,
+        None => return Err(usage_error("Missing the file name")),
+    };
+
+    options.analyse = command == Command::Check;
+    // Asserts are left out of the compiled stories, unless asked for
+    options.debug = command != Command::Build || debug;
+    Ok(Args {
+        command, file_name, format, options, graph_format, output, check, input, expected, bless, answers, coverage, path_limit, ui, load, save, saves,
+        depth_limit, undo_depth, record, replay, headless
+    })
+}
+
+fn name(command: Command) -> &'static str {
+    match command {
+        Command::Run => "run",
+        Command::Check => "check",
+        Command::Build => "build",
+        Command::Graph => "graph",
+        Command::Fmt => "fmt",
+        Command::Test => "test",
+        Command::Explore => "explore",
+    }
+}
+
+fn usage_error(message: &str) -> i32 {
+    eprintln!("Error: {}", message);
+    eprintln!("{}", USAGE.lines().next().unwrap_or_default());
+    eprintln!("For more information, try '--help'");
+    2
+}
+
+fn read(file_name: &str) -> Result<Vec<u8>, i32> {
+    fs::read(file_name).map_err(|error| {
+        eprintln!("Error: Error while reading '{}': {}", file_name, error);
+        1
+    })
+}
+
+fn write(file_name: &str, contents: &[u8]) -> Result<(), i32> {
+    fs::write(file_name, contents).map_err(|error| {
+        eprintln!("Error: Error while writing '{}': {}", file_name, error);
+        1
+    })
+}
+
+// The rpg source file, compiled stories aren't accepted
+fn source(args: &Args) -> Result<String, i32> {
+    let bytes = read(&args.file_name)?;
+    if Program::is_compiled(&bytes) {
+        eprintln!(
+            "Error: '{}' is already compiled, `{}` needs the source",
+            args.file_name, name(args.command)
+        );
+        return Err(2);
+    }
+
+    String::from_utf8(bytes).map_err(|_| {
+        eprintln!("Error: '{}' is not valid UTF-8", args.file_name);
+        1
+    })
+}
+
+// rpg compiler returns either Result<Program, Vec<Error>>
+// Program be the byte code, along with the warnings
+fn compile_source(args: &Args, source: &str) -> Result<Program, i32> {
+    match compile_with(source, &args.options) {
+        Ok(program) => {
+            report(&program.warnings, &args.file_name, source, args.format);
+            Ok(program)
+        },
+        Err(errors) => {
+            report(&errors, &args.file_name, source, args.format);
+            Err(COMPILE_ERROR)
+        },
+    }
+}
+
+// Either an rpg source file or a story compiled by `build`
+fn program(args: &Args) -> Result<Program, i32> {
+    let bytes = read(&args.file_name)?;
+    if !Program::is_compiled(&bytes) {
+        return compile_source(args, &source(args)?);
+    }
+
+    Program::from_bytes(&bytes).map_err(|error| {
+        eprintln!("Error: Invalid compiled story '{}': {}", args.file_name, error);
+        1
+    })
+}
+
+// What the turns of a story go through, besides the game itself
+struct Session {
+    slots: Slots,
+    autosave: bool,
+    recorder: Option<Recorder<fs::File>>,
+}
+
+fn run(args: &Args) -> Result<(), i32> {
+    let program = program(args)?;
+    let slots = Slots::new(match &args.saves {
+        Some(saves) => PathBuf::from(saves),
+        None => Path::new(&args.file_name).with_extension("saves"),
+    });
+
+    let mut game = Game::new(program);
+    game.undo_depth = args.undo_depth.unwrap_or(UNDO_DEPTH);
+    let mut warnings = vec![];
+    if let Some(load) = &args.load {
+        // Saves of the slots could be loaded by their name only, like `slot1.sav`
+        let path = match Path::new(load).exists() {
+            true => PathBuf::from(load),
+            false => slots.directory.join(load),
+        };
+        let bytes = read(&path.to_string_lossy())?;
+        warnings = game.load(&bytes).map_err(|error| {
+            eprintln!("Error: Couldn't load '{}': {}", load, error);
+            1
+        })?;
+    }
+
+    let recorder = match &args.record {
+        Some(record) => Some(Recorder::create(record, game.story_hash).map_err(|error| {
+            eprintln!("Error: Error while writing '{}': {}", record, error);
+            1
+        })?),
+        None => None,
+    };
+    let mut session = Session { slots, autosave: args.replay.is_none(), recorder };
+
+    if let Some(log) = &args.replay {
+        for warning in warnings {
+            eprintln!("Warning: {}", warning);
+        }
+        return replay(log, &mut game, &mut session, args);
+    }
+
+    let ui = args.ui.unwrap_or(if io::stdout().is_terminal() { Ui::Curses } else { Ui::Plain });
+    match ui {
+        Ui::Curses => play(Curses::new(), &mut game, &mut session, &warnings, args),
+        Ui::Plain => play(Plain::stdio(), &mut game, &mut session, &warnings, args),
+    }
+}
+
+fn play<F: Frontend>(
+    mut frontend: F, game: &mut Game, session: &mut Session, warnings: &[String], args: &Args
+) -> Result<(), i32> {
+    let result = warn(&mut frontend, warnings)
+        .and_then(|()| turns(&mut frontend, game, session, args));
+
+    // The terminal is restored before complaining
+    drop(frontend);
+    result.map_err(|error| {
+        eprintln!("Error: {}", error);
+        1
+    })
+}
+
+// The inputs of the session log are played in place of the player, printing the
+// transcript unless `--headless`, the log ending before the story being fine
+fn replay(log: &str, game: &mut Game, session: &mut Session, args: &Args) -> Result<(), i32> {
+    let output: Box<dyn Write> = match args.headless {
+        true => Box::new(io::sink()),
+        false => Box::new(io::stdout()),
+    };
+    let mut replay = Replay::open(log, game.story_hash, output).map_err(|error| {
+        eprintln!("Error: Couldn't replay '{}': {}", log, error);
+        1
+    })?;
+    if replay.is_edited {
+        eprintln!("Warning: '{}' has changed since the session was recorded", args.file_name);
+    }
+
+    match turns(&mut replay, game, session, args) {
+        Ok(()) if !replay.is_finished() => {
+            let (line, _) = &replay.inputs[replay.position];
+            eprintln!("Error: The story ended before the session did, at line {} of '{}'", line, log);
+            Err(1)
+        },
+        Ok(()) => Ok(()),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof && replay.is_finished() => Ok(()),
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            Err(1)
+        },
+    }
+}
+
+// The progress is saved every time the story waits on the player
+// and autosaved before every branch, a crash costing a single choice at most
+fn turns<F: Frontend>(
+    frontend: &mut F, game: &mut Game, session: &mut Session, args: &Args
+) -> io::Result<()> {
+    loop {
+        let interaction = game.step();
+        if let Some(save) = &args.save {
+            fs::write(save, game.save()).map_err(|error| writing(save, error))?;
+        }
+        if let (Interaction::Choice(..), true) = (&interaction, session.autosave) {
+            session.slots.autosave(game).map_err(|error| {
+                writing(&session.slots.path(0).to_string_lossy(), error)
+            })?;
+        }
+
+        match interact(frontend, interaction.clone())? {
+            // Undos are inputs like any other, while loads can't be played back
+            Some(Input::Pause) => match pause_menu(frontend, game, &session.slots)? {
+                MenuAction::Resume => (),
+                MenuAction::Undo => {
+                    if let Some(recorder) = &mut session.recorder {
+                        recorder.record(&interaction, &Input::Undo)?;
+                    }
+                },
+                MenuAction::Load => {
+                    if session.recorder.take().is_some() {
+                        frontend.tell("Loading a save can't be replayed, the session isn't recorded anymore")?;
+                    }
+                },
+                MenuAction::Quit => return Ok(()),
+            },
+            Some(input) => {
+                if let Some(recorder) = &mut session.recorder {
+                    recorder.record(&interaction, &input)?;
+                }
+                game.resume(input)?;
+            },
+            None => return Ok(()),
+        }
+    }
+}
+
+fn writing(file_name: &str, error: io::Error) -> io::Error {
+    io::Error::new(error.kind(), format!("Error while writing '{}': {}", file_name, error))
+}
+
+fn check(args: &Args) -> Result<(), i32> {
+    compile_source(args, &source(args)?)?;
+    Ok(())
+}
+
+// The file next to the story, named after it
+fn sibling(args: &Args, extension: &str) -> String {
+    Path::new(&args.file_name).with_extension(extension).to_string_lossy().into_owned()
+}
+
+fn build(args: &Args) -> Result<(), i32> {
+    let program = compile_source(args, &source(args)?)?;
+    let output = args.output.clone().unwrap_or_else(|| sibling(args, "rpgc"));
+
+    write(&output, &program.to_bytes())
+}
+
+fn graph_command(args: &Args) -> Result<(), i32> {
+    let program = program(args)?;
+    let format = args.graph_format.unwrap_or(GraphFormat::Dot);
+    print!("{}", graph(&program, &args.file_name, format));
+    Ok(())
+}
+
+fn fmt(args: &Args) -> Result<(), i32> {
+    let source = source(args)?;
+    let formatted = match format(&source) {
+        Ok(formatted) => formatted,
+        Err(errors) => {
+            report(&errors, &args.file_name, &source, args.format);
+            return Err(COMPILE_ERROR);
+        },
+    };
+
+    if formatted == source {
+        return Ok(());
+    }
+    if args.check {
+        eprintln!("'{}' is not formatted", args.file_name);
+        return Err(1);
+    }
+    write(&args.file_name, formatted.as_bytes())
+}
+
+// The test blocks of the story, then the golden transcript if it has one
+fn test(args: &Args) -> Result<(), i32> {
+    let program = program(args)?;
+    let mut coverage = Coverage::default();
+    let mut result = Ok(());
+    for test in &program.tests {
+        match run_test(&program, test, &mut coverage) {
+            Ok(()) => println!("test {}: {} ... ok", args.file_name, test.name),
+            Err(error) => {
+                println!("test {}: {} ... FAILED", args.file_name, test.name);
+                println!("{}:{}", args.file_name, error);
+                result = Err(1);
+            },
+        }
+    }
+
+    let expected_name = args.expected.clone().unwrap_or_else(|| sibling(args, "expected"));
+    let is_golden = program.tests.is_empty() || Path::new(&expected_name).exists()
+        || args.bless || args.input.is_some() || args.expected.is_some();
+    if is_golden {
+        result = result.and(golden(args, program.clone(), &expected_name, &mut coverage));
+    }
+    if args.coverage {
+        report_coverage(args, &program, &coverage)?;
+    }
+    result
+}
+
+// Golden transcript test, a story without an input file being played without any
+fn golden(
+    args: &Args, program: Program, expected_name: &str, coverage: &mut Coverage
+) -> Result<(), i32> {
+    let input_name = args.input.clone().unwrap_or_else(|| sibling(args, "input"));
+
+    let input = match fs::read_to_string(&input_name) {
+        Ok(input) => input,
+        Err(error) if error.kind() == io::ErrorKind::NotFound && args.input.is_none() => String::new(),
+        Err(error) => {
+            eprintln!("Error: Error while reading '{}': {}", input_name, error);
+            return Err(1);
+        },
     };
 
-    // rpg compiler returns either Result<Vec<Message>, Error>
-    // Vec<Message> be the ast
-    match rpg_rs::compile(source) {
-        Ok(messages) => {
-            raw();
+    let actual = transcript(program, &input, coverage).map_err(|error| {
+        eprintln!("Error: '{}' failed: {}", args.file_name, error);
+        1
+    })?;
+    if args.bless {
+        return write(expected_name, actual.as_bytes());
+    }
+
+    let expected = match fs::read_to_string(expected_name) {
+        Ok(expected) => expected,
+        Err(error) => {
+            eprintln!("Error: Error while reading '{}': {}", expected_name, error);
+            eprintln!("The transcript could be written into it with `--bless`");
+            return Err(1);
+        },
+    };
 
-            let mut game = Game::new(initscr(), messages);
-            game.window.keypad(true);
-            start_game(&mut game);
+    if actual == expected {
+        println!("test {} ... ok", args.file_name);
+        return Ok(());
+    }
+    println!("test {} ... FAILED", args.file_name);
+    println!("--- {}\n+++ transcript", expected_name);
+    print!("{}", diff(&expected, &actual));
+    Err(1)
+}
 
-            endwin();
-            Ok(())
+fn explore_command(args: &Args) -> Result<(), i32> {
+    let program = program(args)?;
+    let mut options = ExploreOptions::default();
+    if let Some(file_name) = &args.answers {
+        let answers = String::from_utf8(read(file_name)?).map_err(|_| {
+            eprintln!("Error: '{}' is not valid UTF-8", file_name);
+            1
+        })?;
+        canned_answers(&answers, &mut options);
+    }
+    if let Some(limit) = args.path_limit {
+        options.path_limit = limit;
+    }
+    if let Some(limit) = args.depth_limit {
+        options.depth_limit = limit;
+    }
+
+    let exploration = explore(program.clone(), &options);
+    for problem in &exploration.problems {
+        println!("problem: {}", problem.message);
+        let paths = if problem.paths == 1 { "path" } else { "paths" };
+        match problem.inputs.is_empty() {
+            true => println!("  along {} {}, right from the start", problem.paths, paths),
+            false => println!("  along {} {}, the shortest being", problem.paths, paths),
         }
+        for input in &problem.inputs {
+            println!("    {}", input);
+        }
+        println!();
+    }
+
+    println!(
+        "explore {}: {} paths, {} endings, {} loops, {} problems",
+        args.file_name, exploration.paths, exploration.endings, exploration.loops,
+        exploration.problems.len()
+    );
+    if !exploration.is_complete {
+        println!(
+            "Stopped after {} paths, more could be explored with `--path-limit`", options.path_limit
+        );
+    }
+
+    if args.coverage {
+        report_coverage(args, &program, &exploration.coverage)?;
+    }
 
-        Err(error) => error.complain()
+    match exploration.problems.is_empty() {
+        true => Ok(()),
+        false => Err(1),
+    }
+}
+
+// A line each, `<question> => <answer>`, questions being given as many answers as needed
+// `* => <answer>` answers the questions without any
+fn canned_answers(answers: &str, options: &mut ExploreOptions) {
+    let mut default_answers = Vec::new();
+    for line in answers.lines() {
+        let (question, answer) = match line.rsplit_once(" => ") {
+            Some(pair) => pair,
+            None => continue,
+        };
+        match question.trim() {
+            "*" => default_answers.push(answer.to_string()),
+            question => {
+                options.answers.entry(question.to_string()).or_default().push(answer.to_string());
+            },
+        }
     }
 
+    if !default_answers.is_empty() {
+        options.default_answers = default_answers;
+    }
 }
 
+// The summary, and the source annotated with the times each line was reached next to the story
+fn report_coverage(args: &Args, program: &Program, coverage: &Coverage) -> Result<(), i32> {
+    print!("\n{}", coverage_summary(program, coverage, &args.file_name));
+    let bytes = read(&args.file_name)?;
+    if Program::is_compiled(&bytes) {
+        println!("Compiled stories have no source to annotate");
+        return Ok(());
+    }
+
+    let annotated_name = sibling(args, "cov");
+    write(&annotated_name, annotate(program, coverage, &String::from_utf8_lossy(&bytes)).as_bytes())?;
+    println!("The source annotated with the coverage is in '{}'", annotated_name);
+    Ok(())
+}
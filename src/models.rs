@@ -1,22 +1,199 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
 use std::iter::Peekable;
+use std::str::Chars;
+use std::sync::Arc;
 use std::vec::IntoIter;
 
-use pancurses::Window;
-
-pub static DIGITS: [char; 10] = ['1', '2', '3', '4', '5', '6', '7', '8', '9', '0'];
+use crate::bytecode::story_hash;
 
+// Running state of a story, driven through `step` and `resume`
+// The program is shared, copies of the game only copying where it is
+#[derive(Clone)]
 pub struct Game {
-    pub window: Window,
-    pub byte_code: Vec<OpCode>,
-    pub states: HashMap<usize, String>,
+    pub program: Arc<Program>,
+    pub story_hash: u64,    // Of the program, telling saves and sessions of other stories apart
+    pub call_stack: Vec<Frame>,
+    pub iptr: usize,
+    pub path: Vec<usize>,   // Options chosen at `iptr`, leading to the branch handler to run
+    pub waiting: bool,      // The instruction being run waits for an input
+    pub last_message: String,
+    pub history: VecDeque<Snapshot>,    // Questions and choices answered, the latest last
+    pub undo: bool,         // The story allows stepping back
+    pub undo_depth: usize,  // Snapshots kept at most
+    pub call_depth: usize,  // Blocks called into at most, the story failing past it
+    pub coverage: Option<Coverage>,     // Tracked only when it's there
 }
 
+// Choices and questions answered kept to step back to, by default
+pub const UNDO_DEPTH: usize = 20;
+
+// Blocks called into at most, by default
+// Stories loop by calling blocks again from their branches, it's only reached by blocks
+// calling themselves without ever waiting on the player
+pub const CALL_DEPTH: usize = 100_000;
+
 impl Game {
-    pub fn new(window: Window, byte_code: Vec<OpCode>) -> Self {
+    pub fn new(program: impl Into<Arc<Program>>) -> Self {
+        let program = program.into();
+        Self {
+            undo: program.undo,
+            story_hash: story_hash(&program.byte_code),
+            program,
+            call_stack: vec![Frame::new(0)],
+            iptr: 0,
+            path: vec![],
+            waiting: false,
+            last_message: String::new(),
+            history: VecDeque::new(),
+            undo_depth: UNDO_DEPTH,
+            call_depth: CALL_DEPTH,
+            coverage: None,
+        }
+    }
+
+    // Frame of the block currently running
+    pub fn frame(&self) -> &Frame {
+        self.call_stack.last().unwrap()
+    }
+
+    pub fn frame_mut(&mut self) -> &mut Frame {
+        self.call_stack.last_mut().unwrap()
+    }
+}
+
+// The game as it was, waiting on a question or a choice
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub call_stack: Vec<Frame>,
+    pub iptr: usize,
+    pub path: Vec<usize>,
+    pub last_message: String,
+}
+
+// Times each tell, ask and branch option was reached, by the line and column it starts at
+#[derive(Clone, Debug, Default)]
+pub struct Coverage {
+    pub visits: HashMap<(usize, usize), usize>,
+}
+
+impl Coverage {
+    pub fn visit(&mut self, span: &Span) {
+        *self.visits.entry((span.start.line, span.start.column)).or_default() += 1;
+    }
+
+    pub fn merge(&mut self, other: &Coverage) {
+        for (&position, &visits) in &other.visits {
+            *self.visits.entry(position).or_default() += visits;
+        }
+    }
+}
+
+// What the story waits on, before it could go any further
+#[derive(Clone, Debug, PartialEq)]
+pub enum Interaction {
+    Message(String),
+    Question(String),
+    Choice(String, Vec<String>),
+    End,
+    Failed(String),     // An assertion of the story failed, it can't go on
+}
+
+// Answer to an `Interaction`, `Continue` being for the messages
+// `Pause` leaves the interaction pending, for the host to show a menu
+// `Undo` steps back to the last question or choice answered
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    Continue,
+    Answer(String),
+    Choice(usize),
+    Pause,
+    Undo,
+}
+
+// Why `resume` refused the input, the interaction staying pending
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResumeError {
+    NotWaiting,     // `step` wasn't called since the last input
+    Failed,         // The story has failed, `step` returning why
+    Ended,
+    Unexpected(Input, Interaction), // The input doesn't answer the interaction
+}
+
+impl fmt::Display for ResumeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResumeError::NotWaiting => write!(f, "nothing is waiting for an input, `step` comes first"),
+            ResumeError::Failed => write!(f, "the story has failed, `step` returns why"),
+            ResumeError::Ended => write!(f, "the story has ended"),
+            ResumeError::Unexpected(input, interaction) => {
+                write!(f, "{:?} doesn't answer {:?}", input, interaction)
+            },
+        }
+    }
+}
+
+impl std::error::Error for ResumeError {}
+
+// For the frontends, whose errors stop the game all the same
+impl From<ResumeError> for io::Error {
+    fn from(error: ResumeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+// What a frontend got from the player, who could ask for the pause menu
+// or to step back instead
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply<T> {
+    Answer(T),
+    Pause,
+    Undo,
+}
+
+impl<T> Reply<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Reply<U> {
+        match self {
+            Reply::Answer(answer) => Reply::Answer(f(answer)),
+            Reply::Pause => Reply::Pause,
+            Reply::Undo => Reply::Undo,
+        }
+    }
+}
+
+// Metadata of a save, readable without loading it
+#[derive(Clone, Debug, PartialEq)]
+pub struct SaveInfo {
+    pub timestamp: u64,     // Seconds since the unix epoch
+    pub last_message: String,
+}
+
+// The user interface the story is played through
+// Templates are already rendered into plain text by the runtime
+// An error, like the input ending early, stops the game
+pub trait Frontend {
+    // Shows the message, returning once the player has read it
+    fn tell(&mut self, message: &str) -> io::Result<Reply<()>>;
+
+    // Shows the question, returning the answer typed in
+    fn ask(&mut self, question: &str) -> io::Result<Reply<String>>;
+
+    // Shows the question with the options, returning the index of the one chosen
+    fn choose(&mut self, question: &str, options: &[String]) -> io::Result<Reply<usize>>;
+}
+
+// Runtime identifier pool of a block call
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub return_address: usize,
+    pub states: HashMap<usize, String>,
+}
+
+impl Frame {
+    pub fn new(return_address: usize) -> Self {
         Self {
-            window,
-            byte_code,
+            return_address,
             states: HashMap::new(),
         }
     }
@@ -27,19 +204,22 @@ pub enum OpCode {
     NOP,
     END,
     JMP(usize),
-    TELL(String),
-    ASK(String, Option<usize>),
-    BRANCH(String, Vec<Branch>),
+    TELL(Template),
+    ASK(Template, Option<usize>),
+    BRANCH(Template, Vec<Branch>),
+    CALL(usize, Vec<usize>),
+    RET,
+    ASSERT(Template, Template),
 }
 
 #[derive(Clone, Debug)]
 pub struct Branch {
-    pub option: String,
+    pub option: Template,
     pub handler: OpCode,
 }
 
 impl Branch {
-    pub fn new(option: String, handler: OpCode) -> Self {
+    pub fn new(option: Template, handler: OpCode) -> Self {
         Self {
             option,
             handler,
@@ -47,10 +227,76 @@ impl Branch {
     }
 }
 
+// StringLiteral compiled into literal pieces and identifier references
+#[derive(Clone, Debug, Default)]
+pub struct Template {
+    pub pieces: Vec<Piece>,
+    pub span: Span,
+}
+
 #[derive(Clone, Debug)]
+pub enum Piece {
+    Literal(String),
+    Reference(String, usize), // Identifier, spot in the frame
+}
+
+// Writing the template back as it's StringLiteral contents
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(literal) => write!(f, "{}", literal.replace('$', "$$"))?,
+                Piece::Reference(identifier, _) => write!(f, "${}$", identifier)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Position {
     pub line: usize,
-    pub column: usize,
+    pub column: usize,  // Counted in chars
+    pub offset: usize,  // Counted in bytes
+}
+
+impl Position {
+    // Moving past `letter`
+    pub fn advance(&mut self, letter: char) {
+        self.offset += letter.len_utf8();
+        if letter == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+    }
+}
+
+// Range of the source, `end` being exclusive
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    pub fn new(start: Position, end: Position) -> Self {
+        Self {
+            start,
+            end,
+        }
+    }
+
+    // Span covering both `self` and `other`
+    pub fn to(&self, other: &Span) -> Self {
+        Self::new(self.start.clone(), other.end.clone())
+    }
+
+    // The exact slice of source this span covers
+    pub fn slice<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.start.offset..self.end.offset]
+    }
 }
 
 #[derive(PartialEq, Clone, Debug)]
@@ -66,94 +312,265 @@ pub enum TokenType {
     AssignmentOp,           // :=
     Identifier(String),     // <a-zA-Z0-9>
     StringLiteral(String),  // ""
+    Label(String),          // @<a-zA-Z0-9>
+    EqualOp,                // ==
 }
 
-pub struct Lexer {
-    pub source: String,
-    pub index: usize,
-    pub line: usize,
-    pub column: usize,
+pub struct Lexer<'a> {
+    pub chars: Peekable<Chars<'a>>,
+    pub position: Position, // Of the next char
+    pub comments: Vec<(Span, String)>,
+    pub errors: Vec<Error>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Token {
-    pub position: Position,
+    pub span: Span,
     pub token_type: TokenType,
 }
 
+// A named block, `address` being the start of it's byte code
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub name: String,
+    pub span: Span,
+    pub parameters: Vec<String>,
+    pub address: usize,
+    pub end: usize,     // Past the `RET` of the block
+    pub used: bool,
+    pub variables: Vec<Variable>,   // Of the frame the block runs in, parameters first
+}
+
+// A variable of a frame by it's name, for the saves and the tests to find it's spot
+// Only the ones declared at or before an instruction are reachable from it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub spot: usize,
+    pub address: usize, // Where it's declared
+}
+
+// A declared value, `spot` being it's place in the frame
+#[derive(Clone, Debug)]
+pub struct Value {
+    pub name: String,
+    pub span: Span,
+    pub spot: usize,
+    pub used: bool,
+    pub is_parameter: bool,
+}
+
 pub type ParseableTokens = Peekable<IntoIter<Token>>;
 pub struct Parser {
     pub tokens: ParseableTokens,
     pub byte_code: Vec<OpCode>,
+    pub spans: Vec<Span>,       // Source of each instruction of `byte_code`
+    pub ids: Vec<String>,       // Stable identifier of each instruction of `byte_code`
+    pub scope: String,          // Identifier the statements being parsed are nested in
+    pub index: usize,           // Statements parsed so far in `scope`
+    pub node: String,           // Identifier of the statement being parsed
+    pub emitted: usize,         // Instructions emitted so far for `node`
+    pub labels: HashMap<String, Span>,
+    pub variables: Vec<Variable>,   // Of the top level frame
+    pub tests: Vec<Test>,
+    pub debug: bool,            // Asserts are compiled in
+    pub in_test: bool,          // References are resolved by name when tested
+    pub tested: Vec<(String, Span)>,    // Referenced by the tests, checked once all are declared
     pub curr_token: Token,
-    pub value_identifiers: HashMap<String, usize>,
-    pub block_identifiers: HashMap<String, usize>,
+    pub value_count: usize,
+    pub values: Vec<Value>,
+    pub blocks: Vec<Block>,
+    pub defining: Vec<usize>,   // Blocks whose body is being parsed
+    pub errors: Vec<Error>,
+    pub warnings: Vec<Error>,
+    pub value_identifiers: HashMap<String, usize>,  // Indices into `values`
+    pub block_identifiers: HashMap<String, usize>,  // Indices into `blocks`
+}
+
+// Compiled story, along with the warnings found in it
+// `spans` maps every instruction back to the source it was compiled from
+// `ids` names every instruction after the structure of the source, like `main.2.1`,
+// for saves to survive edits of the story
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub byte_code: Vec<OpCode>,
+    pub spans: Vec<Span>,
+    pub ids: Vec<String>,
+    pub blocks: Vec<Block>,
+    pub undo: bool,             // Stepping back is allowed, unless a `// no-undo` comment says otherwise
+    pub variables: Vec<Variable>,   // Of the top level frame, the blocks keeping their own
+    pub tests: Vec<Test>,
+    pub warnings: Vec<Error>,
+}
+
+impl Program {
+    // Variables of the frame running the instruction at `address`, the ones of the
+    // innermost block defined around it
+    pub fn variables(&self, address: usize) -> &[Variable] {
+        self.blocks.iter()
+            .filter(|block| (block.address..block.end).contains(&address))
+            .max_by_key(|block| block.address)
+            .map_or(&self.variables, |block| &block.variables)
+    }
+}
+
+// `test "name" { ... }` block, played by the `test` command only
+// The tests are left out of the compiled stories
+#[derive(Clone, Debug)]
+pub struct Test {
+    pub name: String,
+    pub span: Span,
+    pub steps: Vec<Step>,
+}
+
+// Templates of the steps refer to the variables in scope, wherever the story is
+#[derive(Clone, Debug)]
+pub enum Step {
+    Answer(Template),
+    Choose(Template),
+    Shown(Template),            // The message has been shown so far
+    Assert(Template, Template),
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+pub struct CompileOptions {
+    pub error_limit: usize, // Errors reported at most
+    pub analyse: bool,      // Checking the reachability of the compiled story
+    pub debug: bool,        // Keeping the asserts
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            error_limit: 20,
+            analyse: false,
+            debug: true,
+        }
+    }
+}
+
+// How the explorer plays the story
+pub struct ExploreOptions {
+    pub answers: HashMap<String, Vec<String>>,  // Canned answers, by the question asked
+    pub default_answers: Vec<String>,           // For the questions without any
+    pub step_limit: usize,  // Instructions run without waiting on the player, before giving up
+    pub depth_limit: usize, // Questions and choices along a single path, before giving up
+    pub path_limit: usize,  // Paths explored at most
+}
+
+impl Default for ExploreOptions {
+    fn default() -> Self {
+        Self {
+            answers: HashMap::new(),
+            default_answers: vec![String::new()],
+            step_limit: 100_000,
+            depth_limit: 1000,
+            path_limit: 100_000,
+        }
+    }
+}
+
+// Every path the explorer took, and what went wrong along them
+#[derive(Clone, Debug, Default)]
+pub struct Exploration {
+    pub paths: usize,
+    pub endings: usize,     // Paths reaching the end of the story
+    pub loops: usize,       // Paths coming back to where they already were, through a choice
+    pub problems: Vec<Problem>,
+    pub is_complete: bool,  // Every path was explored, within `path_limit`
+    pub coverage: Coverage,
+}
+
+// The inputs are `answer "..."` and `choose "..."` steps, like in the test blocks
+#[derive(Clone, Debug)]
+pub struct Problem {
+    pub message: String,
+    pub inputs: Vec<String>,    // Along the shortest path running into it
+    pub paths: usize,           // Paths running into it
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageFormat {
+    Human,  // Rendered with source snippets onto stderr
+    Json,   // One JSON object per line onto stdout
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum ErrorType {
     InvalidSyntax,
     Missing(String),
     Expected(String),
-    InvalidIdentifier(usize),
+    InvalidIdentifier(String),
+    UndeclaredIdentifier(String),
+    InvalidJump(usize),
+    DuplicateLabel(String),
+
+    // Warnings
+    UnusedVariable(String),
+    UnusedBlock(String),
+    Shadowing(String),
+    DuplicateOption(String),
+    SingleOption,
+    UnreachableCode,
+    UncalledBlock(String),
+    EndlessBlock(String),
+    EndlessJump,
 }
 
+#[derive(Clone, Debug)]
 pub struct Error {
-    error_type: ErrorType,
-    line: usize,
-    column: usize,
+    pub error_type: ErrorType,
+    pub severity: Severity,
+    pub span: Span,
+    pub help: Option<String>,
 }
 
 impl Error {
-    pub fn lex_error(error_type: ErrorType, lexer: &Lexer) -> Self {
-        Self {
-            error_type,
-            line: lexer.line,
-            column: lexer.column,
+    // Pointing at the next char of the lexer
+    pub fn lex_error(error_type: ErrorType, lexer: &mut Lexer) -> Self {
+        let start = lexer.position.clone();
+        let mut end = start.clone();
+        if let Some(&letter) = lexer.chars.peek() {
+            end.advance(letter);
         }
+
+        Self::at(error_type, Span::new(start, end))
     }
 
     pub fn parse_error(error_type: ErrorType, parser: &Parser) -> Self {
+        Self::at(error_type, parser.curr_token.span.clone())
+    }
+
+    pub fn at(error_type: ErrorType, span: Span) -> Self {
         Self {
             error_type,
-            line: parser.curr_token.position.line,
-            column: parser.curr_token.position.column,
+            severity: Severity::Error,
+            span,
+            help: None,
         }
     }
 
-    pub fn complain(self) -> Result<(), i32> {
-        match self.error_type {
-            ErrorType::InvalidSyntax =>{
-                eprintln!(
-                    "Error: Invalid Syntax\nAt line: {}, column: {}",
-                    self.line, self.column
-                );
-                Err(-1)
-            },
-
-            ErrorType::Missing(error) => {
-                eprintln!(
-                    "Error: Missing {}\nAt line: {}, column: {}",
-                    error, self.line, self.column
-                );
-                Err(40)
-            },
-
-            ErrorType::Expected(error) => {
-                eprintln!(
-                    "Error: Expected {}\nAt line: {}, column: {}",
-                    error, self.line, self.column
-                );
-                Err(41)
-            },
-
-            ErrorType::InvalidIdentifier(identifier) => {
-                eprintln!(
-                    "Error: Identifer '{}' used in StringLiteral without delaration
-At line: {}, column: {}",
-                    identifier, self.line, self.column
-                );
-                Err(42)
-            },
+    pub fn warning(error_type: ErrorType, span: Span) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::at(error_type, span)
         }
     }
+
+    // Note shown under the source snippet
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
 }
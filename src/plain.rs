@@ -0,0 +1,153 @@
+use std::io::{self, BufRead, Write};
+
+use crate::models::*;
+
+// Line oriented frontend, for pipes, screen readers and dumb terminals
+// Messages are printed as they are, options as numbered lines
+pub struct Plain<R: BufRead, W: Write> {
+    pub input: R,
+    pub output: W,
+    pub echo: bool, // Writing the input read into the output too, for transcripts
+}
+
+impl Plain<io::StdinLock<'static>, io::Stdout> {
+    pub fn stdio() -> Self {
+        Self::new(io::stdin().lock(), io::stdout())
+    }
+}
+
+impl<R: BufRead, W: Write> Plain<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output, echo: false }
+    }
+
+    // A line of input, without the line ending
+    // `:undo` steps back and `:menu` opens the pause menu, `::` standing for a leading ':'
+    fn read_line(&mut self) -> io::Result<Reply<String>> {
+        write!(self.output, "> ")?;
+        self.output.flush()?;
+
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof, "input ended before the story did"
+            ));
+        }
+
+        let length = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(length);
+        if self.echo {
+            writeln!(self.output, "{}", line)?;
+        }
+        Ok(match line.as_str() {
+            ":undo" => Reply::Undo,
+            ":menu" => Reply::Pause,
+            _ if line.starts_with("::") => Reply::Answer(line[1..].to_string()),
+            _ => Reply::Answer(line),
+        })
+    }
+}
+
+impl<R: BufRead, W: Write> Frontend for Plain<R, W> {
+    fn tell(&mut self, message: &str) -> io::Result<Reply<()>> {
+        writeln!(self.output, "{}\n", message)?;
+        self.output.flush()?;
+        Ok(Reply::Answer(()))
+    }
+
+    fn ask(&mut self, question: &str) -> io::Result<Reply<String>> {
+        writeln!(self.output, "{}", question)?;
+        let answer = self.read_line()?;
+        writeln!(self.output)?;
+        Ok(answer)
+    }
+
+    // Options could be picked by their number or by their text
+    fn choose(&mut self, question: &str, options: &[String]) -> io::Result<Reply<usize>> {
+        writeln!(self.output, "{}", question)?;
+        for (i, option) in options.iter().enumerate() {
+            writeln!(self.output, "{}) {}", i + 1, option)?;
+        }
+
+        loop {
+            let answer = match self.read_line()? {
+                Reply::Answer(answer) => answer,
+                Reply::Pause => return Ok(Reply::Pause),
+                Reply::Undo => return Ok(Reply::Undo),
+            };
+            let answer = answer.trim();
+            let selection = match answer.parse::<usize>() {
+                Ok(number) => number.checked_sub(1).filter(|&i| i < options.len()),
+                Err(_) => options.iter().position(|option| option.eq_ignore_ascii_case(answer)),
+            };
+
+            if let Some(selection) = selection {
+                writeln!(self.output)?;
+                return Ok(Reply::Answer(selection));
+            }
+            writeln!(self.output, "Please enter a number from 1 to {}", options.len())?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> Vec<String> {
+        vec!["Cave".to_string(), "Forest".to_string()]
+    }
+
+    // The reply to a choice given `input`, along with what got printed
+    fn choose(input: &str) -> (io::Result<Reply<usize>>, String) {
+        let mut plain = Plain::new(input.as_bytes(), Vec::new());
+        let reply = plain.choose("Where to?", &options());
+        (reply, String::from_utf8(plain.output).unwrap())
+    }
+
+    #[test]
+    fn choose_by_number() {
+        let (reply, output) = choose("2\n");
+        assert_eq!(reply.unwrap(), Reply::Answer(1));
+        assert_eq!(output, "Where to?\n1) Cave\n2) Forest\n> \n");
+    }
+
+    #[test]
+    fn choose_by_text() {
+        assert_eq!(choose("  forest \r\n").0.unwrap(), Reply::Answer(1));
+        assert_eq!(choose("CAVE\n").0.unwrap(), Reply::Answer(0));
+    }
+
+    #[test]
+    fn choose_asks_again() {
+        let (reply, output) = choose("0\n3\nMountain\n\n1\n");
+        assert_eq!(reply.unwrap(), Reply::Answer(0));
+        assert_eq!(output.matches("Please enter a number from 1 to 2\n").count(), 4);
+    }
+
+    #[test]
+    fn choose_at_the_end_of_input() {
+        let error = choose("9\n").0.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn echo() {
+        let mut plain = Plain::new("Cave\n".as_bytes(), Vec::new());
+        plain.echo = true;
+        plain.choose("Where to?", &options()).unwrap();
+        assert!(String::from_utf8(plain.output).unwrap().ends_with("> Cave\n\n"));
+    }
+
+    #[test]
+    fn reserved_inputs() {
+        assert_eq!(choose(":undo\n").0.unwrap(), Reply::Undo);
+        assert_eq!(choose(":menu\n").0.unwrap(), Reply::Pause);
+
+        let mut plain = Plain::new(":undo\n:menu\n::menu\n:)\n".as_bytes(), Vec::new());
+        assert_eq!(plain.ask("Name?").unwrap(), Reply::Undo);
+        assert_eq!(plain.ask("Name?").unwrap(), Reply::Pause);
+        assert_eq!(plain.ask("Name?").unwrap(), Reply::Answer(":menu".to_string()));
+        assert_eq!(plain.ask("Name?").unwrap(), Reply::Answer(":)".to_string()));
+    }
+}
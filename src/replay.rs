@@ -0,0 +1,280 @@
+use std::fs;
+use std::io::{self, Write};
+
+use crate::bytecode::invalid;
+use crate::models::*;
+
+// First line of every session log, naming the format and its version
+const HEADER: &str = "rpg-rs session 1";
+
+// Writes every input of the player into a session log, a line each -
+// `continue`, `answer <text>`, `choose <number> <option>` or `undo`
+// The stories having no randomness, there's no seed to keep along with them
+pub struct Recorder<W: Write> {
+    pub output: W,
+}
+
+impl Recorder<fs::File> {
+    pub fn create(file_name: &str, story_hash: u64) -> io::Result<Self> {
+        Self::new(fs::File::create(file_name)?, story_hash)
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(mut output: W, story_hash: u64) -> io::Result<Self> {
+        writeln!(output, "{}", HEADER)?;
+        writeln!(output, "story {:016x}", story_hash)?;
+        Ok(Self { output })
+    }
+
+    // Pausing isn't an input of the story, it's left out
+    pub fn record(&mut self, interaction: &Interaction, input: &Input) -> io::Result<()> {
+        match (interaction, input) {
+            (_, Input::Continue) => writeln!(self.output, "continue")?,
+            (_, Input::Answer(answer)) => writeln!(self.output, "answer {}", escape(answer))?,
+            (Interaction::Choice(_, options), Input::Choice(selection)) => writeln!(
+                self.output, "choose {} {}", selection + 1, escape(&options[*selection])
+            )?,
+            (_, Input::Undo) => writeln!(self.output, "undo")?,
+            _ => return Ok(()),
+        }
+        self.output.flush()
+    }
+}
+
+// Plays the inputs of a session log back, as a frontend
+// The transcript is written into `output`, like the plain frontend does
+pub struct Replay<W: Write> {
+    pub inputs: Vec<(usize, String)>,   // Line number and the line
+    pub position: usize,
+    pub is_edited: bool,                // The story changed since the recording
+    pub output: W,
+}
+
+impl<W: Write> Replay<W> {
+    pub fn open(file_name: &str, story_hash: u64, output: W) -> io::Result<Self> {
+        Self::new(&fs::read_to_string(file_name)?, story_hash, output)
+    }
+
+    pub fn new(log: &str, story_hash: u64, output: W) -> io::Result<Self> {
+        let mut lines = log.lines().enumerate().map(|(i, line)| (i + 1, line.to_string()));
+        if lines.next().map(|(_, line)| line).as_deref() != Some(HEADER) {
+            return Err(invalid("not a session log of an rpg story"));
+        }
+
+        let hash = lines.next().and_then(|(_, line)| {
+            line.strip_prefix("story ").and_then(|hash| u64::from_str_radix(hash, 16).ok())
+        }).ok_or_else(|| invalid("the session log is missing the story hash"))?;
+
+        Ok(Self {
+            inputs: lines.filter(|(_, line)| !line.is_empty()).collect(),
+            position: 0,
+            is_edited: hash != story_hash,
+            output,
+        })
+    }
+
+    // Every input of the session has been played
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.inputs.len()
+    }
+
+    // The next input, as the command and the rest of the line
+    fn next(&mut self, waiting_on: &str) -> io::Result<(usize, String, String)> {
+        let (line, input) = self.inputs.get(self.position).cloned().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "the session ended before the story did")
+        })?;
+        self.position += 1;
+
+        let (command, rest) = input.split_once(' ').unwrap_or((&input, ""));
+        match command {
+            "continue" | "answer" | "choose" | "undo" => {
+                Ok((line, command.to_string(), unescape(rest)))
+            },
+            _ => Err(invalid(&format!(
+                "line {} of the session: unknown input '{}', the story {}", line, command, waiting_on
+            ))),
+        }
+    }
+
+    // About the input just taken
+    fn diverged(&self, waiting_on: &str) -> io::Error {
+        let (line, input) = &self.inputs[self.position - 1];
+        invalid(&format!(
+            "the story diverged from the session at line {}: the story {}, the session has '{}'",
+            line, waiting_on, input
+        ))
+    }
+}
+
+impl<W: Write> Frontend for Replay<W> {
+    fn tell(&mut self, message: &str) -> io::Result<Reply<()>> {
+        writeln!(self.output, "{}\n", message)?;
+        let waiting_on = format!("shows the message \"{}\"", message);
+        let (_, command, _) = self.next(&waiting_on)?;
+        match command.as_str() {
+            "continue" => Ok(Reply::Answer(())),
+            "undo" => Ok(Reply::Undo),
+            _ => Err(self.diverged(&waiting_on)),
+        }
+    }
+
+    fn ask(&mut self, question: &str) -> io::Result<Reply<String>> {
+        writeln!(self.output, "{}", question)?;
+        let waiting_on = format!("asks \"{}\"", question);
+        let (_, command, answer) = self.next(&waiting_on)?;
+        match command.as_str() {
+            "answer" => {
+                writeln!(self.output, "> {}\n", answer)?;
+                Ok(Reply::Answer(answer))
+            },
+            "undo" => Ok(Reply::Undo),
+            _ => Err(self.diverged(&waiting_on)),
+        }
+    }
+
+    fn choose(&mut self, question: &str, options: &[String]) -> io::Result<Reply<usize>> {
+        writeln!(self.output, "{}", question)?;
+        for (i, option) in options.iter().enumerate() {
+            writeln!(self.output, "{}) {}", i + 1, option)?;
+        }
+
+        let waiting_on = format!("asks \"{}\"", question);
+        let (line, command, rest) = self.next(&waiting_on)?;
+        match command.as_str() {
+            "undo" => return Ok(Reply::Undo),
+            "choose" => (),
+            _ => return Err(self.diverged(&waiting_on)),
+        }
+
+        // The option has to be the very same one, at the very same place
+        let (number, option) = rest.split_once(' ').unwrap_or((&rest, ""));
+        let selection = number.parse::<usize>().ok().and_then(|number| number.checked_sub(1));
+        match selection {
+            Some(selection) if options.get(selection).is_some_and(|chosen| chosen == option) => {
+                writeln!(self.output, "> {}\n", number)?;
+                Ok(Reply::Answer(selection))
+            },
+            Some(_) => Err(invalid(&format!(
+                "the story diverged from the session at line {}: option {} \"{}\" no longer exists",
+                line, number, option
+            ))),
+            None => Err(invalid(&format!(
+                "line {} of the session: '{}' isn't an option number", line, number
+            ))),
+        }
+    }
+}
+
+// Inputs are kept on a single line
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut letters = text.chars();
+    while let Some(letter) = letters.next() {
+        if letter != '\\' {
+            result.push(letter);
+            continue;
+        }
+
+        match letters.next() {
+            Some('n') => result.push('\n'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay(inputs: &str) -> Replay<Vec<u8>> {
+        Replay::new(&format!("{}\nstory {:016x}\n{}", HEADER, 7, inputs), 7, Vec::new()).unwrap()
+    }
+
+    fn options() -> Vec<String> {
+        vec!["Cave".to_string(), "Forest".to_string()]
+    }
+
+    #[test]
+    fn recorded_inputs_are_replayed() {
+        let mut recorder = Recorder::new(Vec::new(), 7).unwrap();
+        let choice = Interaction::Choice("Where to?".to_string(), options());
+        recorder.record(&Interaction::Message("Hi".to_string()), &Input::Continue).unwrap();
+        recorder.record(&choice, &Input::Pause).unwrap();
+        recorder.record(&choice, &Input::Choice(1)).unwrap();
+        recorder.record(&choice, &Input::Undo).unwrap();
+        let question = Interaction::Question("Name?".to_string());
+        recorder.record(&question, &Input::Answer("a\\b\nc".to_string())).unwrap();
+
+        let log = String::from_utf8(recorder.output).unwrap();
+        let inputs = "continue\nchoose 2 Forest\nundo\nanswer a\\\\b\\nc\n";
+        assert_eq!(log, format!("{}\nstory 0000000000000007\n{}", HEADER, inputs));
+
+        let mut replay = Replay::new(&log, 7, Vec::new()).unwrap();
+        assert!(!replay.is_edited);
+        assert_eq!(replay.tell("Hi").unwrap(), Reply::Answer(()));
+        assert_eq!(replay.choose("Where to?", &options()).unwrap(), Reply::Answer(1));
+        assert_eq!(replay.choose("Where to?", &options()).unwrap(), Reply::Undo);
+        assert_eq!(replay.ask("Name?").unwrap(), Reply::Answer("a\\b\nc".to_string()));
+        assert!(replay.is_finished());
+
+        let error = replay.tell("Bye").unwrap_err();
+        assert_eq!(error.to_string(), "the session ended before the story did");
+    }
+
+    #[test]
+    fn headers_are_checked() {
+        let error = Replay::new("story 7\ncontinue\n", 7, Vec::new()).err().unwrap();
+        assert_eq!(error.to_string(), "not a session log of an rpg story");
+        let error = Replay::new(&format!("{}\ncontinue\n", HEADER), 7, Vec::new()).err().unwrap();
+        assert_eq!(error.to_string(), "the session log is missing the story hash");
+
+        let log = format!("{}\nstory {:016x}\n", HEADER, 8);
+        assert!(Replay::new(&log, 7, Vec::new()).unwrap().is_edited);
+    }
+
+    #[test]
+    fn divergence_is_reported() {
+        let error = replay("continue\n").ask("Name?").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the story diverged from the session at line 3: the story asks \"Name?\", \
+the session has 'continue'"
+        );
+
+        let error = replay("answer Bob\n").tell("Hi").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the story diverged from the session at line 3: the story shows the message \"Hi\", \
+the session has 'answer Bob'"
+        );
+
+        // Blank lines are skipped, the line numbers staying those of the session
+        let error = replay("\nchoose 1 Mountain\n").choose("Where?", &options());
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "the story diverged from the session at line 4: option 1 \"Mountain\" no longer exists"
+        );
+
+        let error = replay("choose 2 Cave\n").choose("Where?", &options()).unwrap_err();
+        assert!(error.to_string().contains("option 2 \"Cave\" no longer exists"));
+    }
+
+    #[test]
+    fn malformed_sessions() {
+        let error = replay("jump 3\n").tell("Hi").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 3 of the session: unknown input 'jump', the story shows the message \"Hi\""
+        );
+
+        let error = replay("choose one Cave\n").choose("Where?", &options()).unwrap_err();
+        assert_eq!(error.to_string(), "line 3 of the session: 'one' isn't an option number");
+    }
+}
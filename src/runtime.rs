@@ -1,194 +1,434 @@
-pub use crate::*;
-use models::DIGITS;
+use std::io;
 
-pub fn start_game(game: &mut Game) {
-    let byte_code = game.byte_code.clone();
-    let mut iptr = 0;
+use crate::models::*;
+
+// Plays the whole story through the frontend
+pub fn start_game<F: Frontend>(game: &mut Game, frontend: &mut F) -> io::Result<()> {
     loop {
-        let is_end = run(byte_code[iptr], &mut iptr, game);
-        if is_end {
-            break;
+        let interaction = game.step();
+        match interact(frontend, interaction)? {
+            Some(input) => game.resume(input)?,
+            None => return Ok(()),
         }
     }
 }
 
-fn run(op_code: OpCode, iptr: &mut usize, game: &mut Game) -> bool {
-    // Decoding instructions to different functions
-    match op_code {
-        OpCode::NOP => (),
-        OpCode::END => return true,
-        OpCode::JMP(ptr) => {
-            *iptr = ptr;
-            return false;
+// Puts the interaction in front of the player, `None` being the end of the story
+pub fn interact<F: Frontend>(
+    frontend: &mut F, interaction: Interaction
+) -> io::Result<Option<Input>> {
+    let reply = match interaction {
+        Interaction::Message(message) => frontend.tell(&message)?.map(|()| Input::Continue),
+        Interaction::Question(question) => frontend.ask(&question)?.map(Input::Answer),
+        Interaction::Choice(question, options) => {
+            frontend.choose(&question, &options)?.map(Input::Choice)
         },
-        OpCode::TELL(info) => msg_tell(info, game),
-        OpCode::ASK(question, id) => msg_question(question, id, game),
-        OpCode::BRANCH(question, branches) =>
-            return run(msg_branch(question, branches, game), iptr, game),
-    }
+        Interaction::End => return Ok(None),
+        Interaction::Failed(error) => return Err(io::Error::other(error)),
+    };
 
-    *iptr += 1;
-    false
+    Ok(Some(match reply {
+        Reply::Answer(input) => input,
+        Reply::Pause => Input::Pause,
+        Reply::Undo => Input::Undo,
+    }))
 }
 
-fn msg_tell(info: String, game: &Game) {
-    tell_info(info, game);
-    game.window.addstr("\n\nPress any key to continue");
-    game.window.refresh();
-
-    curs_set(0);
-    noecho();
-    game.window.getch();
-    echo();
-    curs_set(1);
-}
+impl Game {
+    // Runs till the story needs the host, returning what it's waiting on
+    // Calling it again without `resume` returns the same interaction
+    pub fn step(&mut self) -> Interaction {
+        loop {
+            if let Some(interaction) = self.step_within(usize::MAX) {
+                return interaction;
+            }
+        }
+    }
 
-fn msg_question(question: String, id: Option<usize>, game: &mut Game) {
-    tell_info(question, game);
+    // Like `step`, giving up with `None` after `limit` instructions not waiting on the player
+    // Calling it again carries on from there
+    pub fn step_within(&mut self, limit: usize) -> Option<Interaction> {
+        for _ in 0..limit {
+            let op_code = self.current().clone();
+            if self.waiting {
+                let interaction = self.interaction(&op_code);
+                if let Interaction::Message(text) | Interaction::Question(text)
+                    | Interaction::Choice(text, _) = &interaction {
+                    self.last_message = text.clone();
+                }
+                return Some(interaction);
+            }
 
-    game.window.addstr("\n\n>");
-    let mut responce = String::new();
-    scan(&game.window, &mut responce);
+            // Instructions referring to variables that aren't set fail the story
+            if self.unset(&op_code).is_some() {
+                self.waiting = true;
+                continue;
+            }
 
-    if let Some(id) = id {
-        game.states.insert(id, responce);
+            // Decoding instructions, the ones needing input wait for `resume`
+            match op_code {
+                OpCode::NOP => self.jump(self.iptr + 1),
+                OpCode::JMP(ptr) => self.jump(ptr),
+                // Blocks calling themselves forever fail the story, instead of running out of memory
+                OpCode::CALL(..) if self.call_stack.len() >= self.call_depth => self.waiting = true,
+                OpCode::CALL(ptr, arguments) => {
+                    self.call(arguments, self.iptr + 1);
+                    self.jump(ptr);
+                },
+                // Returning from the top level is only possible in hand written byte code
+                OpCode::RET if self.call_stack.len() == 1 => self.waiting = true,
+                OpCode::RET => {
+                    let return_address = self.call_stack.pop().unwrap().return_address;
+                    self.jump(return_address);
+                },
+                OpCode::TELL(template) | OpCode::ASK(template, _) => {
+                    self.visit(&template.span);
+                    self.waiting = true;
+                },
+                OpCode::END | OpCode::BRANCH(..) => self.waiting = true,
+                // A failed assert stops the story right there
+                OpCode::ASSERT(left, right) => match self.render(&left) == self.render(&right) {
+                    true => self.jump(self.iptr + 1),
+                    false => self.waiting = true,
+                },
+            }
+        }
+        None
     }
-}
 
-fn msg_branch(question: String, branches: Vec<Branch>, game: &mut Game) -> OpCode {
-    tell_info(question, game);
-    game.window.addstr("\n\n");
+    // Answers the interaction returned by `step`
+    // Input not fitting it is refused, the interaction staying pending
+    pub fn resume(&mut self, input: Input) -> Result<(), ResumeError> {
+        if !self.waiting {
+            return Err(ResumeError::NotWaiting);
+        }
 
-    let branch = branch_selection(branches, game);
+        match (self.current().clone(), input) {
+            (_, Input::Pause) => return Ok(()),
+            (_, Input::Undo) => {
+                self.undo();
+                return Ok(());
+            },
+            (op_code, _) if matches!(self.interaction(&op_code), Interaction::Failed(_)) => {
+                return Err(ResumeError::Failed);
+            },
+            (OpCode::TELL(_), Input::Continue) => self.jump(self.iptr + 1),
+            (OpCode::ASK(_, id), Input::Answer(responce)) => {
+                self.snapshot();
+                if let Some(id) = id {
+                    self.frame_mut().states.insert(id, responce);
+                }
+                self.jump(self.iptr + 1);
+            },
+            // The handler runs in place of the branch
+            (OpCode::BRANCH(_, branches), Input::Choice(selection)) if selection < branches.len() => {
+                self.snapshot();
+                self.visit(&branches[selection].option.span);
+                self.path.push(selection);
+            },
 
-    branch.handler
-}
+            (OpCode::END, _) => return Err(ResumeError::Ended),
+            (op_code, input) => {
+                return Err(ResumeError::Unexpected(input, self.interaction(&op_code)));
+            },
+        }
+
+        self.waiting = false;
+        Ok(())
+    }
 
-fn tell_info(info: String, game: &Game) {
-    game.window.clear();
-    game.window.mv(0, 0);
+    pub fn can_undo(&self) -> bool {
+        self.undo && !self.history.is_empty()
+    }
 
-    let viewable = parse(info, game);
+    // Back to the last question or choice answered, returns false if there's none
+    // Stepping back more than `undo_depth` times isn't possible
+    pub fn undo(&mut self) -> bool {
+        if !self.can_undo() {
+            return false;
+        }
 
-    for letter in viewable.chars() {
-        game.window.addch(letter as u32);
-        thread::sleep(time::Duration::from_millis(15));
-        game.window.refresh();
+        let snapshot = self.history.pop_back().unwrap();
+        self.call_stack = snapshot.call_stack;
+        self.iptr = snapshot.iptr;
+        self.path = snapshot.path;
+        self.last_message = snapshot.last_message;
+        self.waiting = true;
+        true
     }
-}
 
-// Parsing StringLiteral to find an identifer reference
-fn parse(info: String, game: &Game) -> String {
-    let mut i = 0;
-    let mut result = String::new();
-    while i < info.len() {
-        let letter = info.chars().nth(i).unwrap();
-        if letter == '$' {
-            result += &handle_states(&mut i, info, game);
+    // Keeping the game as it is, before answering the question or choice
+    fn snapshot(&mut self) {
+        if !self.undo || self.undo_depth == 0 {
+            return;
+        }
+
+        if self.history.len() >= self.undo_depth {
+            self.history.pop_front();
         }
-        else {
-            result.write_char(letter).unwrap();
-            i += 1;
+        self.history.push_back(Snapshot {
+            call_stack: self.call_stack.clone(),
+            iptr: self.iptr,
+            path: self.path.clone(),
+            last_message: self.last_message.clone(),
+        });
+    }
+
+    fn visit(&mut self, span: &Span) {
+        if let Some(coverage) = &mut self.coverage {
+            coverage.visit(span);
         }
     }
-    result
-}
 
-// Parsing identifer reference inside a StringLiteral
-// And replacing it with it's value from game.states (runtime identifer pool)
-fn handle_states(i: &mut usize, info: String, game: &Game) -> String {
-    *i += 1;
-    let mut result = String::new();
-    if *i < info.len() {
-        let mut letter = info.chars().nth(*i).unwrap();
-        let mut number = String::new();
-        while DIGITS.contains(&letter) {
-            number.write_char(letter).unwrap();
-            *i += 1;
-            if *i >= info.len() { break; }
-            letter = info.chars().nth(*i).unwrap();
+    // The instruction at `iptr`, or the branch handler chosen there
+    pub fn current(&self) -> &OpCode {
+        let mut op_code = &self.program.byte_code[self.iptr];
+        for &selection in &self.path {
+            if let OpCode::BRANCH(_, branches) = op_code {
+                op_code = &branches[selection].handler;
+            }
         }
+        op_code
+    }
+
+    fn jump(&mut self, address: usize) {
+        self.iptr = address;
+        self.path.clear();
+    }
 
-        if !number.is_empty() {
-            let number: usize = number.parse().unwrap();
-            result += &game.states[&number];
+    fn interaction(&self, op_code: &OpCode) -> Interaction {
+        match self.unset(op_code) {
+            Some(Unset::Variable(name, span)) => return Interaction::Failed(format!(
+                "{}:{}: '{}' isn't set", span.start.line, span.start.column, name
+            )),
+            Some(Unset::Argument) => return Interaction::Failed(format!(
+                "'{}': an argument of the call isn't set", self.program.ids[self.iptr]
+            )),
+            None => (),
         }
 
-        else {
-            result.write_char(info.chars().nth(*i-1).unwrap()).unwrap();
+        match op_code {
+            OpCode::TELL(info) => Interaction::Message(self.render(info)),
+            OpCode::ASK(question, _) => Interaction::Question(self.render(question)),
+            OpCode::BRANCH(question, branches) => Interaction::Choice(
+                self.render(question),
+                branches.iter().map(|branch| self.render(&branch.option)).collect(),
+            ),
+            OpCode::ASSERT(left, right) => Interaction::Failed(format!(
+                "{}:{}: assertion failed, \"{}\" isn't \"{}\"",
+                left.span.start.line, left.span.start.column, self.render(left), self.render(right)
+            )),
+            OpCode::RET => Interaction::Failed(format!(
+                "'{}' returns without any block being called", self.program.ids[self.iptr]
+            )),
+            OpCode::CALL(..) => Interaction::Failed(format!(
+                "'{}' calls blocks more than {} deep, a block likely calls itself forever",
+                self.program.ids[self.iptr], self.call_depth
+            )),
+            _ => Interaction::End,
         }
     }
 
-    else {
-        result.write_char(info.chars().nth(*i-1).unwrap()).unwrap();
+    // The first variable referred to by the instruction, that isn't set in the current frame
+    // Only possible in hand written byte code, or when the compiler gets it wrong
+    fn unset<'a>(&self, op_code: &'a OpCode) -> Option<Unset<'a>> {
+        let states = &self.frame().states;
+        let templates: Vec<&Template> = match op_code {
+            OpCode::TELL(template) | OpCode::ASK(template, _) => vec![template],
+            OpCode::BRANCH(question, branches) => {
+                std::iter::once(question).chain(branches.iter().map(|branch| &branch.option)).collect()
+            },
+            OpCode::ASSERT(left, right) => vec![left, right],
+            OpCode::CALL(_, arguments) => {
+                return arguments.iter().any(|argument| !states.contains_key(argument))
+                    .then_some(Unset::Argument);
+            },
+            _ => vec![],
+        };
+
+        templates.into_iter().find_map(|template| {
+            template.pieces.iter().find_map(|piece| match piece {
+                Piece::Reference(name, spot) if !states.contains_key(spot) => {
+                    Some(Unset::Variable(name, &template.span))
+                },
+                _ => None,
+            })
+        })
     }
 
-    result
-}
+    // Pushing a new frame, with the arguments as it's first spots
+    fn call(&mut self, arguments: Vec<usize>, return_address: usize) {
+        let mut frame = Frame::new(return_address);
+        for (spot, argument) in arguments.iter().enumerate() {
+            frame.states.insert(spot, self.frame().states[argument].clone());
+        }
 
-fn branch_selection(branches: Vec<Branch>, game: &Game) -> &Branch {
-    curs_set(0);
-    noecho();
-    let mut selection = 0;
-    let y = game.window.get_cur_y();
-    let x = game.window.get_cur_x();
+        self.call_stack.push(frame);
+    }
 
-    // Looping through all choices in the branch
-    // And rendering them in responce to user
-    loop {
-        game.window.mv(y, x);
-        for (i, branch) in branches.iter().enumerate() {
-            if i == selection {
-                game.window.addstr(format!(">[ {} ]\n", branch.option));
-            } else {
-                game.window.addstr(format!("   {}  \n", branch.option));
+    // Filling the template with values from the current frame (runtime identifer pool)
+    fn render(&self, template: &Template) -> String {
+        let mut result = String::new();
+        for piece in &template.pieces {
+            match piece {
+                Piece::Literal(literal) => result += literal,
+                Piece::Reference(_, spot) => result += &self.frame().states[spot],
             }
         }
+        result
+    }
+}
 
-        match game.window.getch().unwrap() {
-            Input::Character('\n') => break, // Enter / Return
-            Input::KeyDown => selection += 1,
-            Input::KeyUp => if selection != 0 {selection -= 1},
-            _ => (),
-        }
+enum Unset<'a> {
+    Variable(&'a str, &'a Span),
+    Argument,
+}
 
-        if selection >= branches.len() {
-            selection = branches.len() - 1;
-        }
-    
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::compile;
+
+    fn game(source: &str) -> Game {
+        Game::new(compile(source).unwrap())
     }
-    echo();
-    curs_set(1);
-    branches.get(selection).unwrap()
+
+    const STORY: &str = "\
+name := ? \"Name?\"
+greet := (who) { - \"Hi $who$\" }
+# \"Where to, $name$?\" {
+    \"Cave\" => greet(name)
+    \"Forest\" => - \"Trees\"
 }
+";
 
-// Simulating stdin
-fn scan(window: &Window, buffer: &mut String) {
-    noecho();
-    loop {
-        match window.getch().unwrap() {
-            // Enter / Return
-            Input::Character('\n') => {
-                echo();
-                break;
-            },
+    #[test]
+    fn step_and_resume() {
+        let mut game = game(STORY);
+        assert_eq!(game.step(), Interaction::Question("Name?".to_string()));
+        assert_eq!(game.step(), Interaction::Question("Name?".to_string()));
+        game.resume(Input::Answer("Bob".to_string())).unwrap();
 
-            Input::KeyBackspace => {
-                if buffer.len() != 0 {
-                    buffer.pop();
-                    window.mv(window.get_cur_y(), window.get_cur_x() - 1);
-                    window.delch();
-                }
-                continue;
-            },
+        let options = vec!["Cave".to_string(), "Forest".to_string()];
+        assert_eq!(game.step(), Interaction::Choice("Where to, Bob?".to_string(), options));
+        game.resume(Input::Choice(0)).unwrap();
+        assert_eq!(game.step(), Interaction::Message("Hi Bob".to_string()));
+        assert_eq!(game.last_message, "Hi Bob");
+        game.resume(Input::Continue).unwrap();
+        assert_eq!(game.step(), Interaction::End);
+    }
 
-            Input::Character(read) => {
-                window.addch(read);
-                buffer.push(read);
-            },
+    #[test]
+    fn wrong_inputs_are_refused() {
+        let mut game = game(STORY);
+        assert_eq!(game.resume(Input::Continue), Err(ResumeError::NotWaiting));
+
+        let question = game.step();
+        assert_eq!(
+            game.resume(Input::Choice(0)),
+            Err(ResumeError::Unexpected(Input::Choice(0), question.clone()))
+        );
+        assert_eq!(game.step(), question);
+
+        game.resume(Input::Answer("Bob".to_string())).unwrap();
+        let choice = game.step();
+        assert!(matches!(game.resume(Input::Choice(2)), Err(ResumeError::Unexpected(..))));
+        assert_eq!(game.resume(Input::Pause), Ok(()));
+        assert_eq!(game.step(), choice);
 
-            _ => (),
+        game.resume(Input::Choice(1)).unwrap();
+        game.step();
+        game.resume(Input::Continue).unwrap();
+        assert_eq!(game.step(), Interaction::End);
+        assert_eq!(game.resume(Input::Continue), Err(ResumeError::Ended));
+    }
+
+    #[test]
+    fn failed_assert_stops_the_story() {
+        let mut game = game("- \"before\"\nassert \"a\" == \"b\"\n- \"after\"\n");
+        game.step();
+        game.resume(Input::Continue).unwrap();
+        let error = "2:8: assertion failed, \"a\" isn't \"b\"".to_string();
+        assert_eq!(game.step(), Interaction::Failed(error));
+        assert_eq!(game.resume(Input::Continue), Err(ResumeError::Failed));
+    }
+
+    #[test]
+    fn endless_calls_fail_the_story() {
+        let mut game = game("f := {\n    f\n}\nf\n");
+        game.call_depth = 50;
+        let error = "'f.0' calls blocks more than 50 deep, a block likely calls itself forever";
+        assert_eq!(game.step(), Interaction::Failed(error.to_string()));
+        assert_eq!(game.call_stack.len(), 50);
+    }
+
+    #[test]
+    fn step_within_carries_on() {
+        let mut game = game("f := {\n    - \"deep\"\n}\nf\n");
+        assert_eq!(game.step_within(1), None);
+        assert_eq!(game.step_within(10), Some(Interaction::Message("deep".to_string())));
+    }
+
+    // Answers questions with `answer` and picks the first option, till `count` inputs are given
+    fn answer(game: &mut Game, answer: &str, count: usize) {
+        for _ in 0..count {
+            let input = match game.step() {
+                Interaction::Message(_) => Input::Continue,
+                Interaction::Question(_) => Input::Answer(answer.to_string()),
+                Interaction::Choice(..) => Input::Choice(0),
+                interaction => panic!("unexpected {:?}", interaction),
+            };
+            game.resume(input).unwrap();
         }
     }
-}
 
+    #[test]
+    fn undo_steps_back_to_the_last_answer() {
+        let mut game = game(STORY);
+        assert!(!game.undo());
+
+        answer(&mut game, "Bob", 2);
+        assert_eq!(game.step(), Interaction::Message("Hi Bob".to_string()));
+        assert_eq!(game.call_stack.len(), 2);
+
+        game.resume(Input::Undo).unwrap();
+        assert_eq!(game.call_stack.len(), 1);
+        assert!(matches!(game.step(), Interaction::Choice(..)));
+
+        assert!(game.undo());
+        assert_eq!(game.step(), Interaction::Question("Name?".to_string()));
+        answer(&mut game, "Al", 2);
+        assert_eq!(game.step(), Interaction::Message("Hi Al".to_string()));
+        assert_eq!(game.last_message, "Hi Al");
+
+        assert!(game.undo() && game.undo());
+        assert!(!game.undo());
+        assert_eq!(game.last_message, "Name?");
+    }
+
+    #[test]
+    fn undo_depth() {
+        let story = "a := ? \"a\"\nb := ? \"b\"\nc := ? \"c\"\n- \"$a$$b$$c$\"\n";
+        let mut kept = game(story);
+        kept.undo_depth = 2;
+        answer(&mut kept, "x", 3);
+        assert!(kept.undo() && kept.undo());
+        assert!(!kept.undo());
+        assert_eq!(kept.step(), Interaction::Question("b".to_string()));
+
+        let mut unkept = game(story);
+        unkept.undo_depth = 0;
+        answer(&mut unkept, "x", 1);
+        assert!(!unkept.can_undo());
+    }
+
+    #[test]
+    fn undo_disabled_by_the_story() {
+        let mut game = game(&format!("// no-undo\n{}", STORY));
+        answer(&mut game, "Bob", 1);
+        assert!(!game.can_undo());
+        game.step();
+        game.resume(Input::Undo).unwrap();
+        assert!(matches!(game.step(), Interaction::Choice(..)));
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::VecDeque;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bytecode::{invalid, Reader, Writer};
+use crate::models::*;
+
+const MAGIC: &[u8; 4] = b"RPGS";
+const VERSION: u8 = 5;
+
+// Layout of a save, integers being little endian u64s -
+// MAGIC, VERSION, story hash, timestamp, last message, iptr, path, waiting, call stack
+// Instructions are saved by their ids along with what they are, rather than their index,
+// and variables by their names rather than their spots, to survive edits of the story
+impl Game {
+    pub fn save(&self) -> Vec<u8> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+
+        let mut writer = Writer::new(true);
+        writer.header(MAGIC, VERSION);
+        writer.bytes.extend_from_slice(&self.story_hash.to_le_bytes());
+        writer.bytes.extend_from_slice(&timestamp.to_le_bytes());
+        writer.string(&self.last_message);
+
+        writer.string(&self.program.ids[self.iptr]);
+        writer.string(&describe(&self.program.byte_code[self.iptr]));
+        writer.number(self.path.len());
+        for &selection in &self.path {
+            writer.number(selection);
+        }
+        writer.bytes.push(self.waiting as u8);
+
+        writer.number(self.call_stack.len());
+        for (i, frame) in self.call_stack.iter().enumerate() {
+            writer.string(&self.program.ids[frame.return_address]);
+            writer.string(&describe(&self.program.byte_code[frame.return_address]));
+
+            let variables = self.program.variables(self.position(i));
+            let mut states: Vec<_> = frame.states.iter().collect();
+            states.sort();
+            writer.number(states.len());
+            for (spot, value) in states {
+                let (name, rank) = name(variables, *spot);
+                writer.string(name);
+                writer.number(rank);
+                writer.number(*spot);
+                writer.string(value);
+            }
+        }
+
+        writer.bytes
+    }
+
+    // When the save was made and what the player saw last, for the save slots
+    pub fn save_info(bytes: &[u8]) -> io::Result<SaveInfo> {
+        let (_, info, _) = header(bytes)?;
+        Ok(info)
+    }
+
+    // Restores the save into the game, which is left as it is if that fails
+    // Instructions edited out of the story since the save are resumed at the nearest
+    // surviving ones, returning a warning for each of them
+    // Saves of other stories, or of ones changed beyond recognition, are refused
+    pub fn load(&mut self, bytes: &[u8]) -> io::Result<Vec<String>> {
+        let (hash, info, mut reader) = header(bytes)?;
+        let is_edited = hash != self.story_hash;
+        let mut warnings = Vec::new();
+        let mut matches = Matches::default();
+
+        let mut game = Game {
+            program: self.program.clone(),
+            story_hash: self.story_hash,
+            call_stack: vec![],
+            iptr: 0,
+            path: vec![],
+            waiting: false,
+            last_message: info.last_message,
+            history: VecDeque::new(),
+            undo: self.undo,
+            undo_depth: self.undo_depth,
+            call_depth: self.call_depth,
+            coverage: self.coverage.clone(),
+        };
+
+        let id = reader.string()?;
+        let description = reader.string()?;
+        game.path = (0..reader.count()?).map(|_| reader.number()).collect::<io::Result<_>>()?;
+        game.waiting = reader.byte()? != 0;
+
+        // Waiting ones could only be resumed at another instruction waiting for the player
+        let waiting = game.waiting;
+        let is_resumable = |op_code: &OpCode| !waiting || matches!(
+            op_code, OpCode::TELL(_) | OpCode::ASK(..) | OpCode::BRANCH(..) | OpCode::END
+        );
+        game.iptr = game.resolve(
+            &id, &description, |address| is_resumable(&game.program.byte_code[address]),
+            &mut matches, &mut warnings
+        )?;
+        if !game.path.is_empty() && (game.program.ids[game.iptr] != id || !game.is_on_path()) {
+            game.path.clear();
+            warnings.push(format!("the choice made at '{}' is asked again", id));
+        }
+
+        let mut states = Vec::new();
+        for i in 0..reader.count()? {
+            // The first frame is never returned from, it's return address doesn't matter
+            let id = reader.string()?;
+            let description = reader.string()?;
+            let return_address = match i {
+                0 => 0,
+                _ => game.resolve(
+                    &id, &description, |address| game.is_return_address(address),
+                    &mut matches, &mut warnings
+                )?,
+            };
+            game.call_stack.push(Frame::new(return_address));
+
+            let frame_states: Vec<(String, usize, usize, String)> = (0..reader.count()?)
+                .map(|_| {
+                    Ok((reader.string()?, reader.number()?, reader.number()?, reader.string()?))
+                })
+                .collect::<io::Result<_>>()?;
+            states.push(frame_states);
+        }
+
+        if reader.position != bytes.len() {
+            return Err(invalid("trailing bytes after the save"));
+        }
+        if game.call_stack.is_empty() {
+            return Err(invalid("the save has no call stack"));
+        }
+
+        // Spots move around as variables are added, they're found again by the name
+        let program = game.program.clone();
+        for (i, frame_states) in states.into_iter().enumerate() {
+            let position = game.position(i);
+            let variables = program.variables(position);
+            for (name, rank, spot, value) in frame_states {
+                if !is_edited {
+                    game.call_stack[i].states.insert(spot, value);
+                    continue;
+                }
+
+                if name.is_empty() {
+                    continue;
+                }
+                matches.total += 1;
+                match variables.iter().filter(|variable| variable.name == name).nth(rank) {
+                    Some(variable) => {
+                        matches.found += 1;
+                        game.call_stack[i].states.insert(variable.spot, value);
+                    },
+                    None => warnings.push(format!(
+                        "the answer '{}' given to '{}' is lost", value, name
+                    )),
+                }
+            }
+
+            if is_edited {
+                for variable in variables.iter().filter(|variable| variable.address <= position) {
+                    if !game.call_stack[i].states.contains_key(&variable.spot) {
+                        warnings.push(format!(
+                            "'{}' has no value, it was added since the save", variable.name
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Most of it not being found, it's taken to be another story
+        if is_edited && matches.found * 2 < matches.total {
+            return Err(invalid("the save belongs to a different story, or another version of it"));
+        }
+        if is_edited {
+            warnings.insert(0, "the story has changed since the save".to_string());
+        }
+
+        *self = game;
+        Ok(warnings)
+    }
+
+    // Address of the instruction named `id`, or of the nearest `is_allowed` one to it
+    // The statement having moved, it's followed there, otherwise nearest being the one
+    // sharing the most of the id, then the closest statement
+    fn resolve(
+        &self, id: &str, description: &str, is_allowed: impl Fn(usize) -> bool,
+        matches: &mut Matches, warnings: &mut Vec<String>
+    ) -> io::Result<usize> {
+        // Every story starts and ends the same, those tell nothing about it
+        let is_generated = id.starts_with('$');
+        matches.total += !is_generated as usize;
+        let candidates = (0..self.program.ids.len()).filter(|&address| is_allowed(address));
+        let is_same = |address: usize| describe(&self.program.byte_code[address]) == description;
+        let named = candidates.clone().find(|&address| self.program.ids[address] == id);
+        if let Some(address) = named.filter(|&address| is_same(address)) {
+            matches.found += !is_generated as usize;
+            return Ok(address);
+        }
+
+        let moved = candidates.clone().filter(|&address| is_same(address))
+            .min_by_key(|&address| distance(id, &self.program.ids[address]));
+        if let Some(address) = moved {
+            matches.found += 1;
+            warnings.push(format!("'{}' has moved to '{}'", id, self.program.ids[address]));
+            return Ok(address);
+        }
+
+        if let Some(address) = named {
+            warnings.push(format!("'{}' is a different statement since the save", id));
+            return Ok(address);
+        }
+
+        let address = candidates.min_by_key(|&address| distance(id, &self.program.ids[address]))
+            .ok_or_else(|| invalid(&format!("'{}' isn't in the story anymore", id)))?;
+        warnings.push(format!(
+            "'{}' isn't in the story anymore, resuming at '{}' instead", id, self.program.ids[address]
+        ));
+        Ok(address)
+    }
+
+    // Where the frame `i` of the call stack is, the instruction running or the call waiting
+    // on the frame above to return
+    fn position(&self, i: usize) -> usize {
+        match self.call_stack.get(i + 1) {
+            Some(above) => above.return_address - 1,
+            None => self.iptr,
+        }
+    }
+
+    // Calls return right after themselves, the ones inlined into a branch after the branch
+    fn is_return_address(&self, address: usize) -> bool {
+        address > 0 && matches!(self.program.byte_code[address - 1], OpCode::CALL(..) | OpCode::BRANCH(..))
+    }
+
+    // The options chosen have to lead to a handler of the branch at `iptr`
+    fn is_on_path(&self) -> bool {
+        let mut op_code = &self.program.byte_code[self.iptr];
+        for &selection in &self.path {
+            match op_code {
+                OpCode::BRANCH(_, branches) if selection < branches.len() => {
+                    op_code = &branches[selection].handler;
+                },
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+// Ids and variables of the save found in the story, out of all of them
+#[derive(Default)]
+struct Matches {
+    found: usize,
+    total: usize,
+}
+
+// What the instruction is, to tell whether an id still names the same statement
+fn describe(op_code: &OpCode) -> String {
+    match op_code {
+        OpCode::TELL(message) => format!("- \"{}\"", message),
+        OpCode::ASK(question, _) => format!("? \"{}\"", question),
+        OpCode::BRANCH(question, _) => format!("# \"{}\"", question),
+        OpCode::ASSERT(left, right) => format!("assert \"{}\" == \"{}\"", left, right),
+        OpCode::CALL(..) => "call".to_string(),
+        OpCode::JMP(_) => "jump".to_string(),
+        OpCode::NOP => "nop".to_string(),
+        OpCode::RET => "return".to_string(),
+        OpCode::END => "end".to_string(),
+    }
+}
+
+// Name of the variable at `spot`, variables sharing a name being told apart by how many
+// of them come before it
+fn name(variables: &[Variable], spot: usize) -> (&str, usize) {
+    match variables.iter().position(|variable| variable.spot == spot) {
+        Some(index) => {
+            let name = &variables[index].name;
+            (name, variables[..index].iter().filter(|other| other.name == *name).count())
+        },
+        None => ("", 0),
+    }
+}
+
+// The story hash and the metadata, leaving the reader at the game state
+fn header(bytes: &[u8]) -> io::Result<(u64, SaveInfo, Reader<'_>)> {
+    let mut reader = Reader::open(bytes, MAGIC, VERSION, "save of an rpg story")?;
+
+    let hash = u64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+    let timestamp = u64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+    let last_message = reader.string()?;
+    Ok((hash, SaveInfo { timestamp, last_message }, reader))
+}
+
+// How far apart two ids are, as the parts they don't share, then how far apart
+// the first differing statements are, then how much deeper one of them is nested
+fn distance(a: &str, b: &str) -> (usize, usize, usize) {
+    let a: Vec<&str> = a.split('.').collect();
+    let b: Vec<&str> = b.split('.').collect();
+    let common = a.iter().zip(&b).take_while(|(a, b)| a == b).count();
+
+    // `2+1` being the second instruction of the statement `2`
+    let index = |part: Option<&&str>| part.and_then(|part| {
+        part.split('+').next().and_then(|index| index.parse::<usize>().ok())
+    });
+    let apart = match (index(a.get(common)), index(b.get(common))) {
+        (Some(a), Some(b)) => a.abs_diff(b),
+        _ => usize::MAX,
+    };
+
+    (a.len().max(b.len()) - common, apart, a.len().abs_diff(b.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use crate::bytecode::tests::{assert_truncations_refused, corruptions};
+    use crate::lang::compile;
+
+    // Played through the inputs, waiting on what comes next
+    fn play(source: &str, inputs: &[Input]) -> Game {
+        let mut game = Game::new(compile(source).unwrap());
+        for input in inputs {
+            game.step();
+            game.resume(input.clone()).unwrap();
+        }
+        game.step();
+        game
+    }
+
+    #[test]
+    fn save_of_another_story_is_refused() {
+        let story = "name := ? \"Name?\"\n- \"Hi $name$\"\n";
+        let game = play(story, &[Input::Answer("Bob".to_string())]);
+        let other = "q := ? \"Quest?\"\n- \"$q$\"\n";
+        let mut other = Game::new(compile(other).unwrap());
+        assert!(other.load(&game.save()).is_err());
+        assert!(other.call_stack[0].states.is_empty());
+    }
+
+    #[test]
+    fn variables_are_kept_by_name() {
+        let game = play("a := ? \"A\"\n- \"got $a$\"\n", &[Input::Answer("x".to_string())]);
+        let edited = "c := ? \"C\"\na := ? \"A\"\n- \"got $a$\"\n- \"and $c$\"\n";
+        let mut edited = Game::new(compile(edited).unwrap());
+        let warnings = edited.load(&game.save()).unwrap();
+        assert_eq!(warnings[0], "the story has changed since the save");
+        assert!(warnings.iter().any(|warning| warning.contains("'c' has no value")));
+        assert_eq!(edited.program.ids[edited.iptr], "2");
+        assert_eq!(edited.frame().states, HashMap::from([(1, "x".to_string())]));
+    }
+
+    #[test]
+    fn shadowed_variables_are_kept_apart() {
+        let story = "f := {\n    a := ? \"A\"\n    a := ? \"B\"\n    - \"$a$\"\n}\nf\n";
+        let game = play(story, &[Input::Answer("x".to_string()), Input::Answer("y".to_string())]);
+        let edited = story.replace("{\n", "{\n    c := ? \"C\"\n");
+        let mut edited = Game::new(compile(&edited).unwrap());
+        edited.load(&game.save()).unwrap();
+        assert_eq!(edited.frame().states, HashMap::from([
+            (1, "x".to_string()), (2, "y".to_string())
+        ]));
+        assert_eq!(edited.step(), Interaction::Message("y".to_string()));
+    }
+
+    #[test]
+    fn moved_statement_is_followed() {
+        let game = play("- \"one\"\n- \"two\"\n", &[Input::Continue]);
+        let mut edited = Game::new(compile("- \"zero\"\n- \"one\"\n- \"two\"\n").unwrap());
+        let warnings = edited.load(&game.save()).unwrap();
+        assert!(warnings.contains(&"'1' has moved to '2'".to_string()));
+        assert_eq!(edited.step(), Interaction::Message("two".to_string()));
+    }
+
+    const STORY: &str = "\
+name := ? \"Name?\"
+ask := (who) {
+    mood := ? \"How are you, $who$?\"
+    - \"$mood$, got it\"
+}
+# \"Where to?\" {
+    \"Cave\" => ask(name)
+    \"Forest\" => - \"Trees\"
+}
+";
+
+    #[test]
+    fn round_trip() {
+        let inputs = [
+            Input::Answer("Bob".to_string()), Input::Choice(0), Input::Answer("Fine".to_string()),
+        ];
+        let mut game = play(STORY, &inputs);
+        let mut loaded = Game::new(compile(STORY).unwrap());
+        assert!(loaded.load(&game.save()).unwrap().is_empty());
+
+        assert_eq!(loaded.iptr, game.iptr);
+        assert_eq!(loaded.path, game.path);
+        assert_eq!(loaded.waiting, game.waiting);
+        assert_eq!(loaded.last_message, game.last_message);
+        assert_eq!(loaded.call_stack.len(), 2);
+        for (loaded, frame) in loaded.call_stack.iter().zip(&game.call_stack) {
+            assert_eq!(loaded.return_address, frame.return_address);
+            assert_eq!(loaded.states, frame.states);
+        }
+        assert_eq!(loaded.step(), game.step());
+    }
+
+    #[test]
+    fn truncated_save_is_refused() {
+        let game = play(STORY, &[Input::Answer("Bob".to_string()), Input::Choice(0)]);
+        let mut loaded = Game::new(compile(STORY).unwrap());
+        assert_truncations_refused(&game.save(), |bytes| loaded.load(bytes));
+        assert!(loaded.call_stack[0].states.is_empty());
+    }
+
+    // Whether they're refused or not, corrupt saves shouldn't panic
+    #[test]
+    fn corrupt_save_doesnt_panic() {
+        let game = play(STORY, &[Input::Answer("Bob".to_string()), Input::Choice(0)]);
+        let program = Arc::new(compile(STORY).unwrap());
+        for corrupt in corruptions(&game.save()) {
+            let _ = Game::new(program.clone()).load(&corrupt);
+        }
+        assert!(Game::new(program).load(b"not a save").is_err());
+    }
+}
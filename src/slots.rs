@@ -0,0 +1,207 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::models::*;
+use crate::runtime::interact;
+
+// Longest last message shown in a slot
+const PREVIEW_LENGTH: usize = 40;
+
+// Directory of numbered save slots, along with the autosave
+pub struct Slots {
+    pub directory: PathBuf,
+    pub count: usize,
+}
+
+// What the player did in the pause menu, undoing and loading having changed the game
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MenuAction {
+    Resume,
+    Undo,
+    Load,
+    Quit,
+}
+
+impl Slots {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            count: 3,
+        }
+    }
+
+    // Slot 0 being the autosave
+    pub fn path(&self, slot: usize) -> PathBuf {
+        match slot {
+            0 => self.directory.join("autosave.sav"),
+            slot => self.directory.join(format!("slot{}.sav", slot)),
+        }
+    }
+
+    pub fn save(&self, slot: usize, game: &Game) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+        fs::write(self.path(slot), game.save())
+    }
+
+    pub fn autosave(&self, game: &Game) -> io::Result<()> {
+        self.save(0, game)
+    }
+
+    // Warnings being about the story having been edited since the save
+    pub fn load(&self, slot: usize, game: &mut Game) -> io::Result<Vec<String>> {
+        game.load(&fs::read(self.path(slot))?)
+    }
+
+    // `Slot 1 - 2026-10-18 12:30 - You enter the cave...`
+    pub fn label(&self, slot: usize) -> String {
+        let name = match slot {
+            0 => "Autosave".to_string(),
+            slot => format!("Slot {}", slot),
+        };
+
+        let info = match fs::read(self.path(slot)) {
+            Ok(bytes) => Game::save_info(&bytes),
+            Err(_) => return format!("{} - empty", name),
+        };
+        match info {
+            Ok(info) => format!("{} - {} - {}", name, date(info.timestamp), preview(&info.last_message)),
+            Err(_) => format!("{} - unreadable", name),
+        }
+    }
+}
+
+// Pause menu with save, load and quit, shown through the frontend itself
+// Asking for the pause again closes it, and undo is offered while it's possible
+pub fn pause_menu<F: Frontend>(
+    frontend: &mut F, game: &mut Game, slots: &Slots
+) -> io::Result<MenuAction> {
+    loop {
+        let mut options = vec!["Resume", "Save", "Load", "Quit"];
+        if game.can_undo() {
+            options.insert(1, "Undo");
+        }
+
+        let options: Vec<String> = options.into_iter().map(String::from).collect();
+        let selection = match frontend.choose("Paused", &options)? {
+            Reply::Answer(selection) => options[selection].as_str(),
+            Reply::Pause => "Resume",
+            Reply::Undo => "Undo",
+        };
+
+        match selection {
+            "Undo" => return Ok(match game.undo() {
+                true => MenuAction::Undo,
+                false => MenuAction::Resume,
+            }),
+
+            "Save" => {
+                let slot = match choose_slot(frontend, slots, "Save into which slot?", 1)? {
+                    Some(slot) => slot,
+                    None => continue,
+                };
+                let message = match slots.save(slot, game) {
+                    Ok(()) => "Saved".to_string(),
+                    Err(error) => format!("Couldn't save: {}", error),
+                };
+                frontend.tell(&message)?;
+            },
+
+            "Load" => {
+                let slot = match choose_slot(frontend, slots, "Load which slot?", 0)? {
+                    Some(slot) => slot,
+                    None => continue,
+                };
+                match slots.load(slot, game) {
+                    Ok(warnings) => {
+                        warn(frontend, &warnings)?;
+                        return Ok(MenuAction::Load);
+                    },
+                    Err(error) => {
+                        frontend.tell(&format!("Couldn't load: {}", error))?;
+                    },
+                }
+            },
+
+            "Quit" => return Ok(MenuAction::Quit),
+            _ => return Ok(MenuAction::Resume),
+        }
+    }
+}
+
+// Tells the player about a save loaded into an edited story, if it was
+pub fn warn<F: Frontend>(frontend: &mut F, warnings: &[String]) -> io::Result<()> {
+    if warnings.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = "Loaded the save, but".to_string();
+    for warning in warnings {
+        message += &format!("\n- {}", warning);
+    }
+    frontend.tell(&message)?;
+    Ok(())
+}
+
+// Slots from `first` on, `None` going back to the menu
+fn choose_slot<F: Frontend>(
+    frontend: &mut F, slots: &Slots, question: &str, first: usize
+) -> io::Result<Option<usize>> {
+    let mut options: Vec<String> = (first..=slots.count).map(|slot| slots.label(slot)).collect();
+    options.push("Back".to_string());
+
+    let interaction = Interaction::Choice(question.to_string(), options);
+    Ok(match interact(frontend, interaction)? {
+        Some(Input::Choice(selection)) if selection + first <= slots.count => Some(selection + first),
+        _ => None,
+    })
+}
+
+fn preview(message: &str) -> String {
+    let line = message.lines().next().unwrap_or_default();
+    if line.chars().count() <= PREVIEW_LENGTH && line.len() == message.len() {
+        return line.to_string();
+    }
+    let mut result: String = line.chars().take(PREVIEW_LENGTH - 3).collect();
+    result += "...";
+    result
+}
+
+// `YYYY-MM-DD HH:MM` in UTC, from the seconds since the unix epoch
+// Days are turned into a civil date as in Howard Hinnant's `civil_from_days`
+fn date(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let minutes = timestamp % 86400 / 60;
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, minutes / 60, minutes % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(date(0), "1970-01-01 00:00");
+        assert_eq!(date(1709251199), "2024-02-29 23:59");
+        assert_eq!(date(253402300799), "9999-12-31 23:59");
+    }
+
+    // Every fourth year is a leap year, but for the centuries not divisible by 400
+    #[test]
+    fn leap_years() {
+        assert_eq!(date(951782400), "2000-02-29 00:00");
+        assert_eq!(date(4107456000), "2100-02-28 00:00");
+        assert_eq!(date(4107456000 + 86400), "2100-03-01 00:00");
+    }
+}
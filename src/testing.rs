@@ -0,0 +1,258 @@
+use crate::models::*;
+
+// Plays the story along the steps of the test, failing at the first step that doesn't hold
+// Messages are continued past on their own, up to the story waiting after the last step
+// What was reached along the way is added to `coverage`
+pub fn run_test(program: &Program, test: &Test, coverage: &mut Coverage) -> Result<(), String> {
+    let mut game = Game::new(program.clone());
+    game.coverage = Some(Coverage::default());
+    let mut tester = Tester {
+        program,
+        game,
+        shown: vec![],
+        is_recorded: false,
+    };
+    let result = tester.run(test);
+    coverage.merge(tester.game.coverage.as_ref().unwrap());
+    result
+}
+
+// The step not holding, or the story itself failing an assert, with it's position
+enum Failure {
+    Step(String),
+    Story(String),
+}
+
+struct Tester<'a> {
+    program: &'a Program,
+    game: Game,
+    shown: Vec<String>,     // Messages shown so far
+    is_recorded: bool,      // The message waiting is in `shown` already
+}
+
+impl Tester<'_> {
+    fn run(&mut self, test: &Test) -> Result<(), String> {
+        for step in &test.steps {
+            let span = match step {
+                Step::Answer(template) | Step::Choose(template)
+                    | Step::Shown(template) | Step::Assert(template, _) => &template.span,
+            };
+            self.step(step).map_err(|failure| match failure {
+                Failure::Step(error) => format!("{}:{}: {}", span.start.line, span.start.column, error),
+                Failure::Story(error) => error,
+            })?;
+        }
+
+        // Asserts failing before the story waits on the player again fail the test as well
+        self.input().map_err(|failure| match failure {
+            Failure::Step(error) | Failure::Story(error) => error,
+        })?;
+        Ok(())
+    }
+
+    fn step(&mut self, step: &Step) -> Result<(), Failure> {
+        match step {
+            Step::Answer(answer) => match self.input()? {
+                Interaction::Question(_) => {
+                    let answer = self.render(answer)?;
+                    self.resume(Input::Answer(answer))
+                },
+                interaction => Err(Failure::Step(format!(
+                    "expected a question, {}", describe(&interaction)
+                ))),
+            },
+
+            Step::Choose(option) => match self.input()? {
+                Interaction::Choice(question, options) => {
+                    let option = self.render(option)?;
+                    match options.iter().position(|other| *other == option) {
+                        Some(selection) => self.resume(Input::Choice(selection)),
+                        None => Err(Failure::Step(format!(
+                            "\"{}\" isn't an option of \"{}\", the options being \"{}\"",
+                            option, question, options.join("\", \"")
+                        ))),
+                    }
+                },
+                interaction => Err(Failure::Step(format!(
+                    "expected a choice, {}", describe(&interaction)
+                ))),
+            },
+
+            Step::Shown(message) => {
+                self.wait()?;
+                let message = self.render(message)?;
+                match self.shown.contains(&message) {
+                    true => Ok(()),
+                    false => Err(Failure::Step(format!("\"{}\" hasn't been shown", message))),
+                }
+            },
+
+            Step::Assert(left, right) => {
+                self.wait()?;
+                let (left, right) = (self.render(left)?, self.render(right)?);
+                match left == right {
+                    true => Ok(()),
+                    false => Err(Failure::Step(format!(
+                        "assertion failed, \"{}\" isn't \"{}\"", left, right
+                    ))),
+                }
+            },
+        }
+    }
+
+    // Runs the story till it waits, keeping the message it shows
+    fn wait(&mut self) -> Result<Interaction, Failure> {
+        let interaction = self.game.step();
+        match &interaction {
+            Interaction::Message(message) if !self.is_recorded => {
+                self.shown.push(message.clone());
+                self.is_recorded = true;
+            },
+            Interaction::Failed(error) => return Err(Failure::Story(error.clone())),
+            _ => (),
+        }
+        Ok(interaction)
+    }
+
+    // The next question, choice or end of the story
+    fn input(&mut self) -> Result<Interaction, Failure> {
+        loop {
+            match self.wait()? {
+                Interaction::Message(_) => self.resume(Input::Continue)?,
+                interaction => return Ok(interaction),
+            }
+        }
+    }
+
+    fn resume(&mut self, input: Input) -> Result<(), Failure> {
+        self.is_recorded = false;
+        self.game.resume(input).map_err(|error| Failure::Step(error.to_string()))
+    }
+
+    // Variables are looked up by their name, the latest declared before where the story is
+    // waiting on the player, in the frame it's waiting in
+    fn render(&self, template: &Template) -> Result<String, Failure> {
+        let iptr = self.game.iptr;
+        let variables = self.program.variables(iptr);
+        let mut result = String::new();
+        for piece in &template.pieces {
+            match piece {
+                Piece::Literal(literal) => result += literal,
+                Piece::Reference(name, _) => {
+                    let value = variables.iter()
+                        .rfind(|variable| variable.name == *name && variable.address <= iptr)
+                        .and_then(|variable| self.game.frame().states.get(&variable.spot))
+                        .ok_or_else(|| Failure::Step(format!(
+                            "'{}' isn't set at this point of the story", name
+                        )))?;
+                    result += value;
+                },
+            }
+        }
+        Ok(result)
+    }
+}
+
+fn describe(interaction: &Interaction) -> String {
+    match interaction {
+        Interaction::Message(message) => format!("the story shows \"{}\"", message),
+        Interaction::Question(question) => format!("the story asks \"{}\"", question),
+        Interaction::Choice(question, _) => format!("the story asks to choose \"{}\"", question),
+        Interaction::End => "the story has ended".to_string(),
+        Interaction::Failed(error) => error.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::compile_with;
+
+    const STORY: &str = "\
+name := ? \"Name?\"
+greet := (who) {
+    mood := ? \"How are you, $who$?\"
+    - \"$mood$, got it\"
+}
+# \"Where to, $name$?\" {
+    \"Cave\" => greet(name)
+    \"Forest\" => - \"Trees\"
+}
+assert \"$name$\" == \"Bob\"
+";
+
+    // The result of each test, appended to the story
+    fn run(tests: &str) -> Vec<Result<(), String>> {
+        let options = CompileOptions { debug: true, ..CompileOptions::default() };
+        let program = compile_with(&format!("{}{}", STORY, tests), &options).unwrap();
+        let mut coverage = Coverage::default();
+        program.tests.iter().map(|test| run_test(&program, test, &mut coverage)).collect()
+    }
+
+    #[test]
+    fn passing_test() {
+        let results = run("\
+test \"cave\" {
+    answer \"Bob\"
+    choose \"Cave\"
+    assert \"$who$\" == \"Bob\"
+    answer \"Fine\"
+    shown \"Fine, got it\"
+    assert \"$mood$\" == \"Fine\"
+}
+");
+        assert_eq!(results, vec![Ok(())]);
+    }
+
+    #[test]
+    fn failing_steps() {
+        let results = run("\
+test \"kind\" {
+    choose \"Cave\"
+}
+test \"option\" {
+    answer \"Bob\"
+    choose \"Sea\"
+}
+test \"shown\" {
+    answer \"Bob\"
+    choose \"Forest\"
+    shown \"Sea\"
+}
+test \"assert\" {
+    answer \"Al\"
+    assert \"$name$\" == \"Bob\"
+}
+test \"unset\" {
+    answer \"Bob\"
+    assert \"$mood$\" == \"Fine\"
+}
+");
+        assert_eq!(results, vec![
+            Err("12:12: expected a choice, the story asks \"Name?\"".to_string()),
+            Err("16:12: \"Sea\" isn't an option of \"Where to, Bob?\", \
+the options being \"Cave\", \"Forest\"".to_string()),
+            Err("21:11: \"Sea\" hasn't been shown".to_string()),
+            Err("25:12: assertion failed, \"Al\" isn't \"Bob\"".to_string()),
+            Err("29:12: 'mood' isn't set at this point of the story".to_string()),
+        ]);
+
+        // Blocks see their own frame only, outer variables aren't set in there
+        let results = run("\
+test \"frame\" {
+    answer \"Bob\"
+    choose \"Cave\"
+    assert \"$name$\" == \"Bob\"
+}
+");
+        let error = "14:12: 'name' isn't set at this point of the story";
+        assert_eq!(results, vec![Err(error.to_string())]);
+    }
+
+    // The story failing past the last step fails the test too
+    #[test]
+    fn failing_story() {
+        let results = run("test \"forest\" {\n    answer \"Al\"\n    choose \"Forest\"\n}\n");
+        assert_eq!(results, vec![Err("10:8: assertion failed, \"Al\" isn't \"Bob\"".to_string())]);
+    }
+}
@@ -0,0 +1,139 @@
+use std::io;
+
+use crate::models::*;
+use crate::plain::Plain;
+use crate::runtime::start_game;
+
+// Plays the story through the plain frontend, with `input` typed in line by line
+// Returning everything printed, the input included, as the golden transcript
+// What was reached along the way is added to `coverage`
+pub fn transcript(program: Program, input: &str, coverage: &mut Coverage) -> io::Result<String> {
+    let mut plain = Plain::new(input.as_bytes(), Vec::new());
+    plain.echo = true;
+    let mut game = Game::new(program);
+    game.coverage = Some(Coverage::default());
+    let result = start_game(&mut game, &mut plain);
+    coverage.merge(game.coverage.as_ref().unwrap());
+    result?;
+
+    String::from_utf8(plain.output)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "the transcript isn't valid UTF-8"))
+}
+
+// Line by line difference of the transcripts, `-` lines being expected only and
+// `+` lines being there only in the actual one, along with the lines around them
+pub fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+
+    // Longest common subsequence of the lines, from the end onwards
+    let mut common = vec![vec![0; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = match expected[i] == actual[j] {
+                true => common[i + 1][j + 1] + 1,
+                false => common[i + 1][j].max(common[i][j + 1]),
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            lines.push((' ', expected[i]));
+            i += 1;
+            j += 1;
+        }
+        else if i < expected.len() && (j == actual.len() || common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(('-', expected[i]));
+            i += 1;
+        }
+        else {
+            lines.push(('+', actual[j]));
+            j += 1;
+        }
+    }
+
+    // Unchanged lines are only kept next to the changed ones
+    let is_near = |k: usize| {
+        lines[k.saturating_sub(2)..(k + 3).min(lines.len())].iter().any(|&(kind, _)| kind != ' ')
+    };
+    let mut result = String::new();
+    let mut is_skipping = false;
+    for (k, &(kind, line)) in lines.iter().enumerate() {
+        if is_near(k) {
+            result += format!("{} {}", kind, line).trim_end();
+            result.push('\n');
+            is_skipping = false;
+        }
+        else if !is_skipping {
+            result += "  ...\n";
+            is_skipping = true;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::compile;
+
+    fn lines(range: std::ops::Range<usize>) -> String {
+        range.map(|i| format!("line {}\n", i)).collect()
+    }
+
+    #[test]
+    fn changed_line_with_the_lines_around_it() {
+        let actual = lines(1..11).replace("line 5", "line five");
+        assert_eq!(diff(&lines(1..11), &actual), "  ...
+  line 3
+  line 4
+- line 5
++ line five
+  line 6
+  line 7
+  ...
+");
+    }
+
+    #[test]
+    fn added_and_removed_lines() {
+        let expected = lines(1..4);
+        assert_eq!(diff(&expected, &lines(1..5)), "  ...\n  line 2\n  line 3\n+ line 4\n");
+        assert_eq!(diff(&lines(1..5), &expected), "  ...\n  line 2\n  line 3\n- line 4\n");
+        assert_eq!(diff("", "a\n\nb"), "+ a\n+\n+ b\n");
+        assert_eq!(diff("a\n", ""), "- a\n");
+    }
+
+    // Changes close to each other share the lines between them
+    #[test]
+    fn close_changes() {
+        let actual = lines(1..11).replace("line 3", "three").replace("line 7", "seven");
+        assert_eq!(diff(&lines(1..11), &actual), "  line 1
+  line 2
+- line 3
++ three
+  line 4
+  line 5
+  line 6
+- line 7
++ seven
+  line 8
+  line 9
+  ...
+");
+    }
+
+    #[test]
+    fn transcript_echoes_the_input() {
+        let program = compile("name := ? \"Name?\"\n- \"Hi $name$\"\n").unwrap();
+        let mut coverage = Coverage::default();
+        let transcript = transcript(program.clone(), "Bob\n", &mut coverage).unwrap();
+        assert_eq!(transcript, "Name?\n> Bob\n\nHi Bob\n\n");
+
+        let error = super::transcript(program, "", &mut coverage).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}